@@ -0,0 +1,452 @@
+use crate::entries::serialize_entries;
+use crate::layout;
+use crate::paths::agent_socket;
+use crate::utilities::is_wrong_passphrase;
+use age::secrecy::{ExposeSecret, SecretString};
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A request sent by the CLI, one per connection
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Request {
+    /// Get the passphrase and the decrypted entries of a store
+    Load {
+        store: String,
+    },
+    /// Cache the passphrase and the decrypted entries of a store
+    Unlock {
+        store: String,
+        passphrase: String,
+        plaintext: String,
+    },
+    /// Update the cached entries after the CLI saved the store
+    Save {
+        store: String,
+        plaintext: String,
+    },
+    /// Forget all cached secrets
+    Lock,
+    Status,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+enum Response {
+    Ok,
+    /// The store isn't unlocked
+    Locked,
+    Loaded {
+        passphrase: String,
+        plaintext: String,
+        version: String,
+    },
+    Status {
+        stores: Vec<String>,
+    },
+    Error {
+        message: String,
+    },
+}
+
+/// Checks that the directory of the socket is owned by the current user and
+/// only accessible by them, as it may be in a shared folder like /tmp where
+/// another user could have created it first
+fn check_socket_dir(socket: &str) -> Result<()> {
+    let dir = match Path::new(socket).parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let metadata = fs::symlink_metadata(dir)?;
+    if !metadata.is_dir()
+        || metadata.uid() != unsafe { libc::getuid() }
+        || metadata.mode() & 0o777 != 0o700
+    {
+        return Err(anyhow!(
+            "the folder {} of the agent socket should be owned by the current user with mode 0700",
+            dir.display()
+        ));
+    }
+    Ok(())
+}
+
+/// Sends a request to the agent, returns `None` if it isn't running
+fn request(request: &Request) -> Result<Option<Response>> {
+    let socket = agent_socket()?;
+    if !Path::new(&socket).exists() {
+        return Ok(None);
+    }
+    check_socket_dir(&socket)?;
+    let mut stream = match UnixStream::connect(&socket) {
+        Ok(stream) => stream,
+        Err(e)
+            if matches!(
+                e.kind(),
+                io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused
+            ) =>
+        {
+            return Ok(None)
+        }
+        Err(e) => return Err(e).with_context(|| format!("Failed to connect to agent {}", socket)),
+    };
+    // never hand secrets to an agent run by another user
+    if peer_uid(&stream)? != unsafe { libc::getuid() } {
+        return Err(anyhow!(
+            "the agent listening on {} belongs to another user",
+            socket
+        ));
+    }
+    // decrypting a store that changed on disk runs scrypt in the agent
+    stream.set_read_timeout(Some(Duration::from_secs(60)))?;
+    serde_json::to_writer(&mut stream, request)?;
+    stream.shutdown(std::net::Shutdown::Write)?;
+    let response: Response =
+        serde_json::from_reader(&mut stream).context("Invalid response from agent")?;
+    match response {
+        Response::Error { message } => Err(anyhow!("agent: {}", message)),
+        response => Ok(Some(response)),
+    }
+}
+
+/// Whether the agent keeps a store. Stores kept remotely aren't kept, as
+/// checking them for changes takes credentials the agent doesn't have.
+fn keeps(store: &str) -> Result<bool> {
+    Ok(!layout::open(Path::new(store))?.remote())
+}
+
+/// Gets the passphrase, the decrypted entries and the version of a store from
+/// the agent, if it is running and the store is unlocked
+pub fn load(store: &str) -> Result<Option<(SecretString, String, String)>> {
+    if !keeps(store)? {
+        return Ok(None);
+    }
+    let response = request(&Request::Load {
+        store: store.to_string(),
+    })?;
+    match response {
+        Some(Response::Loaded {
+            passphrase,
+            plaintext,
+            version,
+        }) => Ok(Some((SecretString::from(passphrase), plaintext, version))),
+        _ => Ok(None),
+    }
+}
+
+/// Hands an unlocked store to the agent, does nothing if it isn't running
+pub fn unlock(store: &str, passphrase: &SecretString, plaintext: String) -> Result<()> {
+    if !keeps(store)? {
+        return Ok(());
+    }
+    request(&Request::Unlock {
+        store: store.to_string(),
+        passphrase: passphrase.expose_secret().to_string(),
+        plaintext,
+    })?;
+    Ok(())
+}
+
+/// Tells the agent about the new contents of a saved store
+pub fn save(store: &str, plaintext: String) -> Result<()> {
+    if !keeps(store)? {
+        return Ok(());
+    }
+    request(&Request::Save {
+        store: store.to_string(),
+        plaintext,
+    })?;
+    Ok(())
+}
+
+/// Makes the agent forget all secrets, returns false if it isn't running
+pub fn lock() -> Result<bool> {
+    Ok(request(&Request::Lock)?.is_some())
+}
+
+/// Returns the stores unlocked in the agent, or `None` if it isn't running
+pub fn status() -> Result<Option<Vec<String>>> {
+    match request(&Request::Status)? {
+        Some(Response::Status { stores }) => Ok(Some(stores)),
+        Some(_) => Err(anyhow!("unexpected response from agent")),
+        None => Ok(None),
+    }
+}
+
+/// A buffer which is kept out of swap and zeroed when dropped
+struct LockedBuffer(Vec<u8>);
+
+impl LockedBuffer {
+    fn new(bytes: Vec<u8>) -> LockedBuffer {
+        // best effort, mlock fails e.g. when RLIMIT_MEMLOCK is exceeded
+        unsafe { libc::mlock(bytes.as_ptr() as *const libc::c_void, bytes.capacity()) };
+        LockedBuffer(bytes)
+    }
+
+    fn as_str(&self) -> Result<&str> {
+        Ok(std::str::from_utf8(&self.0)?)
+    }
+}
+
+impl Drop for LockedBuffer {
+    fn drop(&mut self) {
+        for byte in self.0.iter_mut() {
+            // volatile, so that the writes aren't optimized away
+            unsafe { std::ptr::write_volatile(byte, 0) };
+        }
+        unsafe { libc::munlock(self.0.as_ptr() as *const libc::c_void, self.0.capacity()) };
+    }
+}
+
+/// A store unlocked in the agent
+struct Unlocked {
+    passphrase: LockedBuffer,
+    plaintext: LockedBuffer,
+    /// Version of the encrypted store the plaintext belongs to
+    version: String,
+    unlocked_at: Instant,
+    used_at: Instant,
+}
+
+impl Unlocked {
+    fn new(passphrase: String, plaintext: String, version: String) -> Unlocked {
+        let now = Instant::now();
+        Unlocked {
+            passphrase: LockedBuffer::new(passphrase.into_bytes()),
+            plaintext: LockedBuffer::new(plaintext.into_bytes()),
+            version,
+            unlocked_at: now,
+            used_at: now,
+        }
+    }
+}
+
+/// How long secrets are kept
+#[derive(Clone, Copy)]
+pub struct Timeouts {
+    /// Since the store was last used
+    pub idle: Duration,
+    /// Since the store was unlocked
+    pub absolute: Duration,
+}
+
+type Stores = Arc<Mutex<HashMap<String, Unlocked>>>;
+
+fn store_version(store: &str) -> Result<String> {
+    layout::open(Path::new(store))?.version()
+}
+
+fn handle(request: Request, stores: &Stores) -> Result<Response> {
+    let mut stores = stores.lock().map_err(|_| anyhow!("agent state poisoned"))?;
+    match request {
+        Request::Load { store } => {
+            let version = store_version(&store)?;
+            let unlocked = match stores.get_mut(&store) {
+                Some(unlocked) => unlocked,
+                None => return Ok(Response::Locked),
+            };
+            if unlocked.version != version {
+                // changed by something other than the CLI, e.g. `git pull`
+                let passphrase = SecretString::from(unlocked.passphrase.as_str()?.to_string());
+                match layout::open(Path::new(&store))?.load(passphrase) {
+                    Ok(storage) => {
+                        let plaintext = serialize_entries(&storage)?;
+                        unlocked.plaintext = LockedBuffer::new(plaintext.into_bytes());
+                        unlocked.version = version;
+                    }
+                    Err(e) if is_wrong_passphrase(&e) => {
+                        stores.remove(&store);
+                        return Ok(Response::Locked);
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            unlocked.used_at = Instant::now();
+            Ok(Response::Loaded {
+                passphrase: unlocked.passphrase.as_str()?.to_string(),
+                plaintext: unlocked.plaintext.as_str()?.to_string(),
+                version: unlocked.version.clone(),
+            })
+        }
+        Request::Unlock {
+            store,
+            passphrase,
+            plaintext,
+        } => {
+            let version = store_version(&store)?;
+            stores.insert(store, Unlocked::new(passphrase, plaintext, version));
+            Ok(Response::Ok)
+        }
+        Request::Save { store, plaintext } => {
+            let version = store_version(&store)?;
+            if let Some(unlocked) = stores.get_mut(&store) {
+                unlocked.plaintext = LockedBuffer::new(plaintext.into_bytes());
+                unlocked.version = version;
+                unlocked.used_at = Instant::now();
+            }
+            Ok(Response::Ok)
+        }
+        Request::Lock => {
+            stores.clear();
+            Ok(Response::Ok)
+        }
+        Request::Status => Ok(Response::Status {
+            stores: stores.keys().cloned().collect(),
+        }),
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn peer_uid(stream: &UnixStream) -> Result<libc::uid_t> {
+    let mut credentials = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut length = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut credentials as *mut libc::ucred as *mut libc::c_void,
+            &mut length,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(credentials.uid)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn peer_uid(stream: &UnixStream) -> Result<libc::uid_t> {
+    let mut uid = 0;
+    let mut gid = 0;
+    if unsafe { libc::getpeereid(stream.as_raw_fd(), &mut uid, &mut gid) } != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(uid)
+}
+
+fn serve_connection(mut stream: UnixStream, stores: &Stores) -> Result<()> {
+    let uid = unsafe { libc::getuid() };
+    let response = if peer_uid(&stream)? != uid {
+        Response::Error {
+            message: "connection refused, the agent belongs to another user".to_string(),
+        }
+    } else {
+        stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+        let mut request = String::new();
+        stream.read_to_string(&mut request)?;
+        if request.is_empty() {
+            // e.g. another `page agent` checking whether the socket is in use
+            return Ok(());
+        }
+        match serde_json::from_str(&request) {
+            Ok(request) => handle(request, stores).unwrap_or_else(|e| Response::Error {
+                message: e.to_string(),
+            }),
+            Err(e) => Response::Error {
+                message: format!("invalid request: {}", e),
+            },
+        }
+    };
+    serde_json::to_writer(&mut stream, &response)?;
+    stream.flush()?;
+    Ok(())
+}
+
+/// Forgets stores whose timeouts expired
+fn expire(stores: &Stores, timeouts: Timeouts) {
+    loop {
+        thread::sleep(Duration::from_secs(1));
+        if let Ok(mut stores) = stores.lock() {
+            stores.retain(|_, unlocked| {
+                unlocked.used_at.elapsed() < timeouts.idle
+                    && unlocked.unlocked_at.elapsed() < timeouts.absolute
+            });
+        }
+    }
+}
+
+/// Creates the socket, only accessible by the current user
+fn bind(socket: &str) -> Result<UnixListener> {
+    if let Some(dir) = Path::new(socket).parent() {
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(dir)?;
+    }
+    check_socket_dir(socket)?;
+    if Path::new(socket).exists() {
+        if UnixStream::connect(socket).is_ok() {
+            return Err(anyhow!("an agent is already listening on {}", socket));
+        }
+        // left behind by an agent which didn't shut down cleanly
+        fs::remove_file(socket)?;
+    }
+    let listener =
+        UnixListener::bind(socket).with_context(|| format!("Failed to bind {}", socket))?;
+    fs::set_permissions(socket, fs::Permissions::from_mode(0o600))?;
+    Ok(listener)
+}
+
+/// Detaches from the terminal, returns false in the process which should exit
+fn daemonize() -> Result<bool> {
+    match fork::daemon(false, true) {
+        Ok(fork::Fork::Child) => {}
+        Ok(fork::Fork::Parent(_)) => return Ok(false),
+        Err(_) => return Err(anyhow!("failed to start the agent in the background")),
+    }
+    // point the standard streams to /dev/null rather than closing them, so
+    // that accepted connections can't end up as stdout or stderr
+    let null = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")?;
+    for fd in 0..3 {
+        unsafe { libc::dup2(null.as_raw_fd(), fd) };
+    }
+    Ok(true)
+}
+
+/// Runs the agent until it is killed
+pub fn run(timeouts: Timeouts, foreground: bool) -> Result<()> {
+    // keep other processes of the user from reading the secrets via ptrace or core dumps
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    unsafe {
+        libc::prctl(libc::PR_SET_DUMPABLE, 0, 0, 0, 0)
+    };
+
+    let socket = agent_socket()?;
+    let listener = bind(&socket)?;
+    println!("Agent listening on {}", socket);
+    if !foreground && !daemonize()? {
+        return Ok(());
+    }
+
+    let stores: Stores = Arc::new(Mutex::new(HashMap::new()));
+    let expiring = Arc::clone(&stores);
+    thread::spawn(move || expire(&expiring, timeouts));
+
+    for stream in listener.incoming() {
+        let result = stream
+            .map_err(anyhow::Error::from)
+            .and_then(|stream| serve_connection(stream, &stores));
+        if let Err(e) = result {
+            // stderr is /dev/null when running in the background
+            let _ = writeln!(io::stderr(), "agent: {}", e);
+        }
+    }
+    Ok(())
+}
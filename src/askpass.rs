@@ -0,0 +1,57 @@
+use crate::paths::askpass_file;
+use anyhow::{anyhow, Context, Result};
+use regex::Regex;
+use serde::Deserialize;
+use std::fs;
+
+/// The rules mapping askpass prompts to entries, read from `askpass.toml`
+#[derive(Debug, Deserialize)]
+pub struct Rules {
+    #[serde(default, rename = "rule")]
+    pub rules: Vec<Rule>,
+}
+
+/// Maps prompts matching `pattern` (a regular expression) to the entry `entry`,
+/// which may refer to capture groups of the pattern, e.g. `ssh/$1`
+#[derive(Debug, Deserialize)]
+pub struct Rule {
+    pub pattern: String,
+    pub entry: String,
+    /// Print this custom field instead of the password
+    pub field: Option<String>,
+}
+
+/// The entry (and optionally the field) answering a prompt
+#[derive(Debug, PartialEq)]
+pub struct Target {
+    pub entry: String,
+    pub field: Option<String>,
+}
+
+impl Rules {
+    pub fn load() -> Result<Self> {
+        let path = askpass_file()?;
+        let content = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read askpass rules from {}", path))?;
+        let rules: Rules =
+            toml::from_str(&content).with_context(|| format!("Failed to parse {}", path))?;
+        Ok(rules)
+    }
+
+    /// Returns the target of the first rule matching the prompt
+    pub fn resolve(&self, prompt: &str) -> Result<Target> {
+        for rule in &self.rules {
+            let pattern = Regex::new(&rule.pattern)
+                .with_context(|| format!("invalid askpass pattern '{}'", rule.pattern))?;
+            if let Some(captures) = pattern.captures(prompt) {
+                let mut entry = String::new();
+                captures.expand(&rule.entry, &mut entry);
+                return Ok(Target {
+                    entry,
+                    field: rule.field.clone(),
+                });
+            }
+        }
+        Err(anyhow!("no askpass rule matches the prompt '{}'", prompt))
+    }
+}
@@ -0,0 +1,40 @@
+use crate::cli::AwsFields;
+use crate::entries::Entry;
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+
+/// The output expected from a `credential_process`, see
+/// https://docs.aws.amazon.com/cli/latest/userguide/cli-configure-sourcing-external.html
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ProcessCredentials {
+    pub version: u8,
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub session_token: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expiration: Option<String>,
+}
+
+impl ProcessCredentials {
+    pub fn from_entry(entry: &Entry, fields: &AwsFields) -> Result<Self> {
+        let required = |name: &String| {
+            entry.fields.get(name).cloned().ok_or_else(|| {
+                anyhow!(
+                    "entry has no field '{}', add it with `page edit <entry> --no-prompt --field {}=<value>`",
+                    name,
+                    name
+                )
+            })
+        };
+
+        Ok(ProcessCredentials {
+            version: 1,
+            access_key_id: required(&fields.access_key_id_field)?,
+            secret_access_key: required(&fields.secret_access_key_field)?,
+            session_token: entry.fields.get(&fields.session_token_field).cloned(),
+            expiration: entry.fields.get(&fields.expiration_field).cloned(),
+        })
+    }
+}
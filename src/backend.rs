@@ -0,0 +1,190 @@
+use crate::paths::{BACKUPS_DIR_NAME, ENTRIES_FILE_NAME};
+use crate::s3::S3Backend;
+use crate::settings::StoreSettings;
+use crate::utilities::to_hex;
+use anyhow::{anyhow, Context, Error, Result};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Keeps the encrypted entries file of a store. Every version of the stored
+/// bytes has an identifier, so that a write can't overwrite changes it didn't
+/// see, e.g. those of another device.
+pub trait Backend {
+    /// Where the bytes are kept, e.g. a path
+    fn location(&self) -> String;
+
+    /// Whether the bytes are kept on another machine, which takes credentials
+    /// to reach
+    fn remote(&self) -> bool;
+
+    /// The stored bytes and their version, `None` if nothing is stored
+    fn read(&self) -> Result<Option<(Vec<u8>, String)>>;
+
+    /// The version of the stored bytes, `None` if nothing is stored
+    fn version(&self) -> Result<Option<String>>;
+
+    /// Stores the bytes if the stored version is still `expected`, `None`
+    /// meaning that nothing is stored yet, and returns their version. Fails
+    /// with `conflict` otherwise.
+    fn write(&self, bytes: &[u8], expected: Option<&str>) -> Result<String>;
+
+    /// Where backups of earlier versions are, newest first
+    fn backups(&self) -> Result<Vec<String>>;
+
+    /// Deletes the backups of earlier versions
+    fn remove_backups(&self) -> Result<()>;
+}
+
+/// Opens the backend of the store in a storage folder, an S3 bucket if the
+/// store has S3 settings
+pub fn open(dir: &Path, settings: &StoreSettings) -> Result<Box<dyn Backend>> {
+    if let Some(s3) = &settings.s3 {
+        return Ok(Box::new(S3Backend::new(s3)?));
+    }
+    Ok(Box::new(FileBackend {
+        path: dir.join(ENTRIES_FILE_NAME),
+        backups_dir: dir.join(BACKUPS_DIR_NAME),
+    }))
+}
+
+/// The error of a write which would overwrite changes it didn't see
+pub fn conflict(location: &str) -> Error {
+    anyhow!(
+        "{} was changed since the store was loaded, run the command again",
+        location
+    )
+}
+
+/// How many earlier versions `FileBackend` keeps
+const MAX_BACKUPS: usize = 10;
+
+/// The entries file in the storage folder, the default. The version is the
+/// SHA-256 of the file, and the previous versions are kept in `backups`.
+struct FileBackend {
+    path: PathBuf,
+    backups_dir: PathBuf,
+}
+
+impl FileBackend {
+    fn read_file(&self) -> Result<Option<Vec<u8>>> {
+        match fs::read(&self.path) {
+            Ok(bytes) => Ok(Some(bytes)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("Failed to read {}", self.location())),
+        }
+    }
+
+    /// The backups by the time they were made, in milliseconds since the
+    /// Unix epoch
+    fn backup_files(&self) -> Result<Vec<(u128, PathBuf)>> {
+        let items = match fs::read_dir(&self.backups_dir) {
+            Ok(items) => items,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e.into()),
+        };
+        let mut backups = vec![];
+        for item in items {
+            let path = item?.path();
+            let millis = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_prefix("entries-"))
+                .and_then(|name| name.strip_suffix(".toml.age"))
+                .and_then(|millis| millis.parse::<u128>().ok());
+            if let Some(millis) = millis {
+                backups.push((millis, path));
+            }
+        }
+        backups.sort_by_key(|backup| std::cmp::Reverse(backup.0));
+        Ok(backups)
+    }
+
+    /// Keeps a copy of the current version and drops the oldest copies
+    fn back_up(&self, bytes: &[u8]) -> Result<()> {
+        fs::create_dir_all(&self.backups_dir)?;
+        let mut millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or(0);
+        // a backup made in the same millisecond takes the next free one
+        let mut file = loop {
+            let path = self
+                .backups_dir
+                .join(format!("entries-{}.toml.age", millis));
+            match fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(path)
+            {
+                Ok(file) => break file,
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => millis += 1,
+                Err(e) => return Err(e.into()),
+            }
+        };
+        file.write_all(bytes)?;
+        for (_, path) in self.backup_files()?.iter().skip(MAX_BACKUPS) {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+}
+
+fn version_of(bytes: &[u8]) -> String {
+    to_hex(&Sha256::digest(bytes))
+}
+
+impl Backend for FileBackend {
+    fn location(&self) -> String {
+        self.path.display().to_string()
+    }
+
+    fn remote(&self) -> bool {
+        false
+    }
+
+    fn read(&self) -> Result<Option<(Vec<u8>, String)>> {
+        Ok(self.read_file()?.map(|bytes| {
+            let version = version_of(&bytes);
+            (bytes, version)
+        }))
+    }
+
+    fn version(&self) -> Result<Option<String>> {
+        Ok(self.read()?.map(|(_, version)| version))
+    }
+
+    /// The file is replaced by renaming a new file over it, so that it is
+    /// never left half written
+    fn write(&self, bytes: &[u8], expected: Option<&str>) -> Result<String> {
+        let current = self.read_file()?;
+        if current.as_deref().map(version_of).as_deref() != expected {
+            return Err(conflict(&self.location()));
+        }
+        if let Some(current) = current {
+            self.back_up(&current)?;
+        }
+        let mut new = self.path.clone().into_os_string();
+        new.push(".new");
+        fs::write(&new, bytes)?;
+        fs::rename(&new, &self.path)?;
+        Ok(version_of(bytes))
+    }
+
+    fn backups(&self) -> Result<Vec<String>> {
+        Ok(self
+            .backup_files()?
+            .into_iter()
+            .map(|(_, path)| path.display().to_string())
+            .collect())
+    }
+
+    fn remove_backups(&self) -> Result<()> {
+        for (_, path) in self.backup_files()? {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+}
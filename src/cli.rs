@@ -1,4 +1,8 @@
-use clap::{Parser, Subcommand, ValueEnum};
+use crate::config::Config;
+use crate::utilities::MAX_WORK_FACTOR;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
 
 #[derive(Parser)]
 #[command(bin_name = "page", version, about)]
@@ -6,24 +10,116 @@ pub struct Cli {
     #[command(subcommand)]
     pub cmd: Cmd,
 
+    #[arg(long, global = true, env = "PAGE_STORE", value_name = "NAME")]
+    /// Use a named store instead of the default one, see `page store`
+    pub store: Option<String>,
+
+    #[command(flatten)]
+    pub unlock: PassphraseOptions,
+}
+
+/// Options controlling how the passphrase is obtained
+#[derive(Args)]
+pub struct PassphraseOptions {
     #[arg(short, long)]
     /// Disable the keyring integration
     pub no_keyring: bool,
+
+    #[arg(long, conflicts_with = "no_keyring")]
+    /// Use the keyring integration even if disabled in the config
+    pub keyring: bool,
+
+    #[arg(long, global = true, env = "PAGE_PINENTRY", value_name = "PROGRAM")]
+    /// Ask for the passphrase with a pinentry program, e.g. `pinentry-gnome3`
+    pub pinentry: Option<String>,
+
+    #[arg(long, global = true, env = "PAGE_PASSPHRASE_FILE", value_name = "PATH")]
+    /// Read the passphrase from the first line of a file
+    pub passphrase_file: Option<PathBuf>,
+
+    #[arg(long, global = true, env = "PAGE_PASSPHRASE_FD", value_name = "FD")]
+    /// Read the passphrase from an open file descriptor (Unix only)
+    pub passphrase_fd: Option<i32>,
+
+    #[arg(
+        long,
+        global = true,
+        env = "PAGE_PASSPHRASE_COMMAND",
+        value_name = "COMMAND"
+    )]
+    /// Read the passphrase from the output of a shell command
+    pub passphrase_command: Option<String>,
+}
+
+impl PassphraseOptions {
+    /// Applies the settings of the config which weren't given on the command
+    /// line or in environment variables
+    pub fn apply_config(&mut self, config: &Config) {
+        if config.keyring == Some(false) && !self.keyring {
+            self.no_keyring = true;
+        }
+        if self.pinentry.is_none() {
+            self.pinentry = config.pinentry.clone();
+        }
+    }
+
+    /// Whether a non-interactive passphrase source was given
+    pub fn is_unattended(&self) -> bool {
+        self.passphrase_file.is_some()
+            || self.passphrase_fd.is_some()
+            || self.passphrase_command.is_some()
+    }
+
+    /// The options unlocking the destination store of `cp` and `mv`, whose
+    /// passphrase is only read from the sources given for it
+    pub fn for_destination(&self, to: &DestinationPassphrase) -> PassphraseOptions {
+        PassphraseOptions {
+            no_keyring: self.no_keyring,
+            keyring: self.keyring,
+            pinentry: self.pinentry.clone(),
+            passphrase_file: to.to_passphrase_file.clone(),
+            passphrase_fd: to.to_passphrase_fd,
+            passphrase_command: to.to_passphrase_command.clone(),
+        }
+    }
+}
+
+/// Non-interactive passphrase sources for the destination store of `cp` and
+/// `mv`, which is otherwise unlocked like a store without them
+#[derive(Args)]
+pub struct DestinationPassphrase {
+    #[arg(long, value_name = "PATH")]
+    /// Read the passphrase of the destination store from the first line of a file
+    pub to_passphrase_file: Option<PathBuf>,
+
+    #[arg(long, value_name = "FD")]
+    /// Read the passphrase of the destination store from an open file descriptor (Unix only)
+    pub to_passphrase_fd: Option<i32>,
+
+    #[arg(long, value_name = "COMMAND")]
+    /// Read the passphrase of the destination store from the output of a shell command
+    pub to_passphrase_command: Option<String>,
 }
 
 #[derive(Subcommand)]
 pub enum Cmd {
     /// Initialize the password store
-    Init,
+    Init {
+        #[command(flatten)]
+        options: InitOptions,
+    },
+    /// Change the passphrase of the store
+    Passwd {
+        #[arg(long, value_name = "LOG_N", value_parser = parse_work_factor)]
+        /// Change the scrypt work factor (log2 of N) as well
+        work_factor: Option<u8>,
+    },
     /// Add a new entry
     New {
         entry_name: String,
 
-        #[arg(long, short)]
-        username: Option<String>,
-
-        #[arg(long)]
-        url: Option<String>,
+        #[command(flatten)]
+        options: EntryOptions,
     },
     /// List all known entries
     List,
@@ -35,9 +131,17 @@ pub enum Cmd {
         #[arg(long, short, value_enum, default_value_t = EntryAttribute::Password)]
         attribute: EntryAttribute,
 
+        #[arg(long, conflicts_with = "attribute")]
+        /// Show a custom field instead of an attribute
+        field: Option<String>,
+
         #[arg(long, short)]
         /// Print instead of copying it to the clipboard
         on_screen: bool,
+
+        #[arg(long, env = "PAGE_CLIPBOARD_TIMEOUT", value_name = "SECONDS")]
+        /// Clear the clipboard after this many seconds, 0 to keep the value (Unix only)
+        clipboard_timeout: Option<u64>,
     },
     /// Edit an entry
     Edit {
@@ -46,11 +150,12 @@ pub enum Cmd {
         #[arg(long, short)]
         new_name: Option<String>,
 
-        #[arg(long, short)]
-        username: Option<String>,
+        #[command(flatten)]
+        options: EntryOptions,
 
-        #[arg(long)]
-        url: Option<String>,
+        #[arg(long, value_name = "NAME")]
+        /// Remove a custom field, can be repeated
+        remove_field: Vec<String>,
 
         #[arg(long)]
         /// Do not display a prompt for entering a new password
@@ -58,17 +163,288 @@ pub enum Cmd {
     },
     /// Remove an entry
     Remove { entry: String },
+    /// Copy an entry, also to another store with `STORE:ENTRY`
+    Cp {
+        #[arg(value_parser = parse_location)]
+        source: EntryLocation,
+
+        #[arg(value_parser = parse_location)]
+        /// The new entry, `STORE:` keeps the name
+        destination: EntryLocation,
+
+        #[command(flatten)]
+        to: DestinationPassphrase,
+    },
+    /// Move or rename an entry, also to another store with `STORE:ENTRY`
+    Mv {
+        #[arg(value_parser = parse_location)]
+        source: EntryLocation,
+
+        #[arg(value_parser = parse_location)]
+        /// The new entry, `STORE:` keeps the name
+        destination: EntryLocation,
+
+        #[command(flatten)]
+        to: DestinationPassphrase,
+    },
+    /// Merge a diverged copy of the entries file, e.g. from another device
+    Merge {
+        /// The other entries file
+        other: PathBuf,
+
+        #[arg(long, value_name = "PATH")]
+        /// The entries file both copies started from, to tell additions from removals
+        base: Option<PathBuf>,
+
+        #[arg(long, value_enum)]
+        /// Resolve conflicts without asking
+        prefer: Option<Prefer>,
+    },
+    /// Rewrite the entries file in canonical form, sorted by entry name
+    Fmt {
+        #[arg(long)]
+        /// Only check that the entries file is canonical
+        check: bool,
+    },
+    /// List the backups of earlier versions of the entries file, newest first
+    Backups,
+    /// Print entries as environment variable assignments
+    Env {
+        /// Only export entries whose name starts with this prefix
+        prefix: String,
+
+        #[arg(long, short, value_enum, env = "PAGE_ENV_FORMAT")]
+        /// The output format, `dotenv` unless configured otherwise
+        format: Option<EnvFormat>,
+    },
+    /// Act as a git credential helper
+    GitCredential {
+        /// One of `get`, `store` or `erase`, other operations are ignored
+        operation: String,
+    },
+    /// Act as a git merge driver merging the entries file entry by entry
+    GitMergeDriver {
+        #[arg(long, conflicts_with_all = ["base", "ours", "theirs", "path"])]
+        /// Set up the driver in the git repository of the store instead
+        install: bool,
+
+        #[arg(long, value_enum)]
+        /// Resolve conflicts instead of leaving them to `page merge`
+        prefer: Option<Prefer>,
+
+        #[arg(required_unless_present = "install")]
+        /// The common ancestor (%O)
+        base: Option<PathBuf>,
+
+        #[arg(required_unless_present = "install")]
+        /// The current version, replaced by the merge result (%A)
+        ours: Option<PathBuf>,
+
+        #[arg(required_unless_present = "install")]
+        /// The other branch's version (%B)
+        theirs: Option<PathBuf>,
+
+        /// The path of the merged file in the repository (%P)
+        path: Option<PathBuf>,
+    },
+    /// Act as a git textconv driver listing the entries of a revision of the
+    /// entries file, without secrets
+    GitDiffTextconv {
+        #[arg(long, conflicts_with = "file")]
+        /// Set up the driver in the git repository of the store instead
+        install: bool,
+
+        #[arg(required_unless_present = "install")]
+        /// The revision of the entries file
+        file: Option<PathBuf>,
+    },
+    /// Act as a docker credential helper
+    DockerCredential {
+        #[arg(value_enum)]
+        operation: DockerCredentialOperation,
+
+        #[arg(long, env = "PAGE_DOCKER_FOLDER", default_value = "docker")]
+        /// Folder (entry name prefix) holding the registry credentials
+        folder: String,
+    },
+    /// Print AWS credentials stored in an entry for `credential_process`
+    AwsCredentials {
+        entry_name: String,
+
+        #[command(flatten)]
+        fields: AwsFields,
+    },
+    /// Act as an askpass program for ssh or sudo
+    Askpass {
+        /// The prompt, mapped to an entry by the rules in `askpass.toml`
+        prompt: String,
+    },
     /// Display status information
     Info,
+    /// Cache unlocked stores in a background agent (Unix only)
+    Agent {
+        #[arg(long, value_name = "SECONDS", default_value_t = 600)]
+        /// Forget a store when it wasn't used for this long
+        idle_timeout: u64,
+
+        #[arg(long, value_name = "SECONDS", default_value_t = 3600)]
+        /// Forget a store this long after it was unlocked
+        timeout: u64,
+
+        #[arg(long)]
+        /// Do not detach from the terminal
+        foreground: bool,
+    },
+    /// Print a session token which unlocks the store for a limited time, to be
+    /// exported as `PAGE_SESSION`
+    Unlock {
+        #[arg(
+            long,
+            env = "PAGE_SESSION_EXPIRY",
+            value_name = "SECONDS",
+            default_value_t = 900
+        )]
+        /// How long the session is valid
+        expiry: u64,
+
+        #[arg(long, short, value_enum, default_value_t = EnvFormat::Sh)]
+        format: EnvFormat,
+    },
+    /// Invalidate all sessions and make the agent forget all unlocked stores
+    Lock,
+    /// Sync the store with a git repository
+    Git {
+        #[command(subcommand)]
+        cmd: GitCmd,
+    },
     /// Keyring related commands
     Keyring {
         #[command(subcommand)]
         cmd: KeyringCmd,
     },
+    /// Manage named stores
+    Store {
+        #[command(subcommand)]
+        cmd: StoreCmd,
+    },
+    /// Get and set configuration
+    Config {
+        #[command(subcommand)]
+        cmd: ConfigCmd,
+    },
     /// Generate shell completion
     Completion { shell: Shell },
 }
 
+/// Settings chosen when creating a store
+#[derive(Args)]
+pub struct InitOptions {
+    #[arg(long, value_name = "PATH")]
+    /// Require an age key file in addition to the passphrase, created if it doesn't exist
+    pub key_file: Option<PathBuf>,
+
+    #[arg(long, value_name = "LOG_N", value_parser = parse_work_factor)]
+    /// The scrypt work factor (log2 of N), picked for this device by default
+    pub work_factor: Option<u8>,
+
+    #[arg(long)]
+    /// Write the entries file ASCII-armored
+    pub armor: bool,
+
+    #[arg(long, value_enum)]
+    /// How the entries are kept, `single-file` by default
+    pub layout: Option<Layout>,
+}
+
+/// How the entries of a store are kept in the storage folder
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Layout {
+    /// All entries in `entries.toml.age`
+    #[default]
+    SingleFile,
+    /// Each entry in its own file in `entries/`, like pass
+    FilePerEntry,
+}
+
+/// Attributes which can be set on `new` and `edit`
+#[derive(Args)]
+pub struct EntryOptions {
+    #[arg(long, short)]
+    pub username: Option<String>,
+
+    #[arg(long)]
+    pub url: Option<String>,
+
+    #[arg(long)]
+    /// Environment variable name used by `page env`
+    pub env_var: Option<String>,
+
+    #[arg(long = "field", value_name = "NAME[=VALUE]", value_parser = parse_field)]
+    /// Set a custom field, can be repeated. Without a value, the value is
+    /// prompted for, so that secrets don't end up in the shell history
+    pub fields: Vec<(String, Option<String>)>,
+}
+
+fn parse_field(field: &str) -> Result<(String, Option<String>), String> {
+    let (name, value) = match field.split_once('=') {
+        Some((name, value)) => (name, Some(value.to_string())),
+        None => (field, None),
+    };
+    if name.is_empty() {
+        return Err("expected NAME or NAME=VALUE".to_string());
+    }
+    Ok((name.to_string(), value))
+}
+
+fn parse_work_factor(work_factor: &str) -> Result<u8, String> {
+    match work_factor.parse() {
+        Ok(log_n) if (1..=MAX_WORK_FACTOR).contains(&log_n) => Ok(log_n),
+        _ => Err(format!("expected a number from 1 to {}", MAX_WORK_FACTOR)),
+    }
+}
+
+/// An entry of a named store, or of the store in use if no store is given
+#[derive(Clone)]
+pub struct EntryLocation {
+    pub store: Option<String>,
+    pub entry: String,
+}
+
+/// Parses `STORE:ENTRY`, `ENTRY` or `:ENTRY` (for entry names containing `:`)
+fn parse_location(location: &str) -> Result<EntryLocation, String> {
+    let (store, entry) = match location.split_once(':') {
+        Some(("", entry)) => (None, entry),
+        Some((store, entry)) => (Some(store.to_string()), entry),
+        None => (None, location),
+    };
+    if store.is_none() && entry.is_empty() {
+        return Err("expected an entry name".to_string());
+    }
+    Ok(EntryLocation {
+        store,
+        entry: entry.to_string(),
+    })
+}
+
+/// Names of the custom fields holding AWS credentials
+#[derive(Args)]
+pub struct AwsFields {
+    #[arg(long, default_value = "aws_access_key_id")]
+    pub access_key_id_field: String,
+
+    #[arg(long, default_value = "aws_secret_access_key")]
+    pub secret_access_key_field: String,
+
+    #[arg(long, default_value = "aws_session_token")]
+    /// Optional, only included if the entry has this field
+    pub session_token_field: String,
+
+    #[arg(long, default_value = "aws_expiration")]
+    /// Optional, an ISO 8601 timestamp, only included if the entry has this field
+    pub expiration_field: String,
+}
+
 #[derive(ValueEnum, Clone)]
 pub enum Shell {
     Bash,
@@ -79,6 +455,38 @@ pub enum Shell {
     Nushell,
 }
 
+#[derive(ValueEnum, Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EnvFormat {
+    /// KEY='value'
+    Dotenv,
+    /// export KEY='value'
+    Sh,
+    /// set -gx KEY 'value'
+    Fish,
+    /// $env.KEY = r#'value'#
+    Nushell,
+}
+
+/// How `page merge` resolves conflicting changes
+#[derive(ValueEnum, Clone, Copy)]
+pub enum Prefer {
+    /// Keep the entry of the store
+    Ours,
+    /// Take the entry of the other file
+    Theirs,
+    /// Take the entry changed last, the store's if unknown
+    Newer,
+}
+
+#[derive(ValueEnum, Clone)]
+pub enum DockerCredentialOperation {
+    Get,
+    Store,
+    Erase,
+    List,
+}
+
 #[derive(ValueEnum, Clone)]
 pub enum EntryAttribute {
     Password,
@@ -86,10 +494,84 @@ pub enum EntryAttribute {
     Url,
 }
 
+#[derive(Subcommand)]
+pub enum GitCmd {
+    /// Makes the storage folder a git repository and commits the store
+    Init {
+        #[arg(long, value_name = "URL")]
+        /// Add a remote named origin to sync with
+        remote: Option<String>,
+    },
+    /// Commits changes, then pulls and pushes
+    Sync,
+    /// Shows the history of the store
+    Log {
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+        /// Arguments for `git log`, e.g. `-p`
+        args: Vec<String>,
+    },
+    /// Shows uncommitted changes and how far the store is ahead or behind
+    Status,
+}
+
 #[derive(Subcommand)]
 pub enum KeyringCmd {
     /// Checks if the keyring integration works
-    Check,
+    Check {
+        #[arg(long)]
+        /// Check all stores with a passphrase in the keyring
+        all: bool,
+    },
+    /// Stores the passphrase in the keyring, after checking that it decrypts the store
+    Set,
     /// Deletes the password from the keyring
-    Forget,
+    Forget {
+        #[arg(long)]
+        /// Forget the passphrases of all stores
+        all: bool,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum ConfigCmd {
+    /// Prints the effective value of a setting
+    Get { key: String },
+    /// Changes a setting of the store, or of the user with `--user`
+    Set {
+        key: String,
+        value: String,
+
+        #[arg(long)]
+        /// Change the user config instead of the store config
+        user: bool,
+    },
+    /// Removes a setting from the store config, or the user config with `--user`
+    Unset {
+        key: String,
+
+        #[arg(long)]
+        /// Change the user config instead of the store config
+        user: bool,
+    },
+    /// Lists the settings made in the store and user config
+    List,
+}
+
+#[derive(Subcommand)]
+pub enum StoreCmd {
+    /// Registers a store under a name
+    Add {
+        name: String,
+        path: PathBuf,
+
+        #[arg(long)]
+        /// Make it the default store
+        default: bool,
+    },
+    /// Unregisters a store, its files are kept
+    Remove { name: String },
+    /// Lists the named stores, marking the one in use with `*`
+    List,
+    /// Makes a store the default
+    Default { name: String },
 }
@@ -1,49 +1,142 @@
-use crate::cli::{self, EntryAttribute};
-use crate::entries::{load_entries, save_entries, Entry, Storage};
+#[cfg(unix)]
+use crate::agent;
+use crate::askpass;
+use crate::aws;
+use crate::cli::{
+    self, AwsFields, DockerCredentialOperation, EntryAttribute, EntryLocation, EntryOptions,
+    EnvFormat, InitOptions, PassphraseOptions, Prefer,
+};
+use crate::config::{self, Scope, UserConfig};
+use crate::docker_credential;
+use crate::entries::{load_entries, read_entries_file, save_entries, Entry, Storage};
+#[cfg(unix)]
+use crate::entries::{parse_entries, serialize_entries};
+use crate::exports;
+use crate::git;
+use crate::git_credential;
 use crate::hooks::{run_hook, Hook, HookEvent};
-use crate::paths::{entries_file, hooks_dir, storage_dir};
-use crate::utilities;
+use crate::layout;
+use crate::merge::{self, Conflict, Side};
+use crate::paths::{self, canonical_storage_dir, hooks_dir, storage_dir, store_settings_file};
+use crate::session;
+use crate::settings::StoreSettings;
+use crate::textconv;
+use crate::utilities::{self, PassphraseSource, PromptOutput};
 use age::secrecy::{ExposeSecret, SecretString};
 use anyhow::{anyhow, Error, Result};
 use clap::CommandFactory;
 use clap_complete::{generate, Shell};
 use clap_complete_nushell::Nushell;
+use std::collections::{BTreeMap, HashMap};
 use std::fs;
 use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-pub fn init(no_keyring: bool) -> Result<(), Error> {
+pub fn init(options: InitOptions, unlock: &PassphraseOptions) -> Result<(), Error> {
     fs::create_dir_all(storage_dir()?)?;
-    let path = entries_file()?;
-    if fs::metadata(&path).is_err() {
-        fs::File::create(entries_file()?)?;
-        println!("Created entries file {}", path);
-        let passphrase = utilities::get_passphrase(no_keyring)?;
-        let entries: Storage = toml::from_str("")?;
-        save_entries(passphrase, &entries)?
+    let store = layout::current()?;
+    let (kind, path) = (store.kind(), store.path());
+    if !store.exists()? {
+        if options.key_file.is_some()
+            || options.work_factor.is_some()
+            || options.armor
+            || options.layout.is_some()
+        {
+            let settings = StoreSettings::load()?;
+            let settings = StoreSettings {
+                key_file: options.key_file.map(|p| init_key_file(&p)).transpose()?,
+                work_factor: options.work_factor,
+                armor: options.armor,
+                layout: options.layout.unwrap_or(settings.layout),
+                ..settings
+            };
+            settings.save()?;
+        }
+        let store = layout::current()?;
+        println!("Created entries {} {}", store.kind(), store.path());
+        let passphrase = utilities::get_passphrase(unlock, PromptOutput::Stdout)?;
+        store.create(passphrase)?
+    } else if options.key_file.is_some() {
+        return Err(anyhow!(
+            "entries {} {} already exists, a key file can only be set up for a new store",
+            kind,
+            path
+        ));
+    } else if options.work_factor.is_some() {
+        return Err(anyhow!(
+            "entries {} {} already exists, use `page passwd` to change the work factor",
+            kind,
+            path
+        ));
+    } else if options.armor {
+        return Err(anyhow!(
+            "entries {} {} already exists, set `armor = true` in {} instead",
+            kind,
+            path,
+            store_settings_file()?
+        ));
+    } else if options.layout.is_some() {
+        return Err(anyhow!(
+            "entries {} {} already exists, the layout can only be chosen for a new store",
+            kind,
+            path
+        ));
+    } else {
+        println!("Entries {} {} already exists", kind, path);
+    }
+    Ok(())
+}
+
+/// Uses an existing key file or creates a new one, returns its absolute path
+fn init_key_file(path: &Path) -> Result<PathBuf> {
+    if path.exists() {
+        utilities::read_key_file(path)?;
     } else {
-        println!("Entries file {} already exists", path);
+        utilities::create_key_file(path)?;
+        println!("Created key file {}", path.display());
+    }
+    Ok(fs::canonicalize(path)?)
+}
+
+pub fn passwd(work_factor: Option<u8>, unlock: &PassphraseOptions) -> Result<()> {
+    before_load(&HookEvent::ChangePassphrase)?;
+    let (old_passphrase, storage) = load_storage(unlock, PromptOutput::Stdout)?;
+
+    let passphrase =
+        utilities::prompt_passphrase("New passphrase: ", unlock, PromptOutput::Stdout)?;
+    let repeated =
+        utilities::prompt_passphrase("Repeat new passphrase: ", unlock, PromptOutput::Stdout)?;
+    if passphrase.expose_secret() != repeated.expose_secret() {
+        return Err(anyhow!("the passphrases don't match"));
     }
+
+    if let Some(work_factor) = work_factor {
+        let mut settings = StoreSettings::load()?;
+        settings.work_factor = Some(work_factor);
+        settings.save()?;
+    }
+    layout::current()?.change_passphrase(old_passphrase, passphrase.clone(), &storage)?;
+    agent_unlock(&passphrase, &storage)?;
+    if !unlock.no_keyring {
+        utilities::store_keyring_passphrase(&passphrase)?;
+    }
+    after_save(&HookEvent::ChangePassphrase, &[])?;
+    println!("Passphrase changed");
     Ok(())
 }
 
 pub fn new_entry(
     entry: String,
-    username: Option<String>,
-    url: Option<String>,
-    no_keyring: bool,
+    options: EntryOptions,
+    unlock: &PassphraseOptions,
 ) -> Result<(), Error> {
-    run_hook(&Hook::PreLoad, &HookEvent::NewEntry)?;
-    let passphrase = utilities::get_passphrase(no_keyring)?;
-    let mut storage = load_entries(passphrase.clone())?;
-
-    if storage.entries.contains_key(&entry) {
-        let overwrite = utilities::read_stdin(&format!(
-            "Entry '{}' already exists. Overwrite (y/N)?",
-            entry
-        ))?;
-        if overwrite.to_uppercase() != "Y" {
-            return Ok(());
-        }
+    check_env_var(&options.env_var)?;
+    before_load(&HookEvent::NewEntry)?;
+    let (passphrase, mut storage) = load_storage(unlock, PromptOutput::Stdout)?;
+
+    if !confirm_overwrite(&storage, &entry)? {
+        return Ok(());
     }
 
     let password = SecretString::from(rpassword::prompt_password_stdout(&format!(
@@ -51,26 +144,24 @@ pub fn new_entry(
         entry
     ))?);
 
-    storage.entries.insert(
-        entry,
-        Entry {
-            password: password.expose_secret().to_string(),
-            username,
-            url,
-        },
-    );
+    let mut new_entry = Entry {
+        password: password.expose_secret().to_string(),
+        ..Default::default()
+    };
+    update_entry(&mut new_entry, options)?;
+    new_entry.touch();
+    storage.entries.insert(entry.clone(), new_entry);
 
-    save_entries(passphrase, &storage)?;
-    run_hook(&Hook::PostSave, &HookEvent::NewEntry)?;
+    save_storage(passphrase, &storage)?;
+    after_save(&HookEvent::NewEntry, &[entry])?;
 
     Ok(())
 }
 
-pub fn list(no_keyring: bool) -> Result<(), Error> {
-    run_hook(&Hook::PreLoad, &HookEvent::ListEntries)?;
+pub fn list(unlock: &PassphraseOptions) -> Result<(), Error> {
+    before_load(&HookEvent::ListEntries)?;
 
-    let passphrase = utilities::get_passphrase(no_keyring)?;
-    let storage = load_entries(passphrase)?;
+    let (_, storage) = load_storage(unlock, PromptOutput::Stdout)?;
     for name in storage.entries.keys() {
         println!("{}", name);
     }
@@ -80,12 +171,17 @@ pub fn list(no_keyring: bool) -> Result<(), Error> {
 pub fn show(
     entry: &str,
     attribute: EntryAttribute,
+    field: Option<String>,
     on_screen: bool,
-    no_keyring: bool,
+    clipboard_timeout: Option<u64>,
+    unlock: &PassphraseOptions,
 ) -> Result<()> {
-    run_hook(&Hook::PreLoad, &HookEvent::ShowEntry)?;
-    let passphrase = utilities::get_passphrase(no_keyring)?;
-    let storage = load_entries(passphrase)?;
+    let clipboard_timeout = match clipboard_timeout {
+        Some(timeout) => timeout,
+        None => config::load()?.clipboard_timeout.unwrap_or(0),
+    };
+    before_load(&HookEvent::ShowEntry)?;
+    let (_, storage) = load_storage(unlock, PromptOutput::Stdout)?;
 
     if storage.entries.contains_key(entry) {
         let entry = storage
@@ -93,18 +189,26 @@ pub fn show(
             .get(entry)
             .ok_or_else(|| anyhow!("entry '{}' not found", entry))?;
 
+        if let Some(field) = field {
+            let value = entry
+                .fields
+                .get(&field)
+                .ok_or_else(|| anyhow!("entry has no field '{}'", field))?;
+            return utilities::reveal(value, on_screen, clipboard_timeout);
+        }
+
         match attribute {
             EntryAttribute::Password => {
-                utilities::reveal(&entry.password, on_screen)?;
+                utilities::reveal(&entry.password, on_screen, clipboard_timeout)?;
             }
             EntryAttribute::Username => {
                 if let Some(u) = &entry.username {
-                    utilities::reveal(u, on_screen)?;
+                    utilities::reveal(u, on_screen, clipboard_timeout)?;
                 }
             }
             EntryAttribute::Url => {
                 if let Some(u) = &entry.url {
-                    utilities::reveal(u, on_screen)?;
+                    utilities::reveal(u, on_screen, clipboard_timeout)?;
                 }
             }
         };
@@ -118,16 +222,16 @@ pub fn show(
 pub fn edit(
     entry_name: String,
     new_name: Option<String>,
-    username: Option<String>,
-    url: Option<String>,
+    options: EntryOptions,
+    remove_fields: Vec<String>,
     no_prompt: bool,
-    no_keyring: bool,
+    unlock: &PassphraseOptions,
 ) -> Result<()> {
-    run_hook(&Hook::PreLoad, &HookEvent::EditEntry)?;
-    let passphrase = utilities::get_passphrase(no_keyring)?;
-    let mut storage = load_entries(passphrase.clone())?;
+    check_env_var(&options.env_var)?;
+    before_load(&HookEvent::EditEntry)?;
+    let (passphrase, mut storage) = load_storage(unlock, PromptOutput::Stdout)?;
 
-    let entry = storage
+    let mut entry = storage
         .entries
         .remove(&entry_name)
         .ok_or_else(|| anyhow!("entry '{}' not found", entry_name))?;
@@ -152,56 +256,606 @@ pub fn edit(
         None => entry_name,
     };
 
-    let username = match username {
-        Some(u) => Some(u),
-        None => entry.username,
+    update_entry(&mut entry, options)?;
+    for field in remove_fields {
+        entry.fields.remove(&field);
+    }
+
+    if !no_prompt {
+        entry.password = rpassword::prompt_password_stdout(&format!("Password for '{}': ", name))?;
+    }
+
+    entry.touch();
+    storage.entries.insert(name.clone(), entry);
+
+    save_storage(passphrase, &storage)?;
+    after_save(&HookEvent::EditEntry, &[name])?;
+
+    Ok(())
+}
+
+pub fn remove(entry: &str, unlock: &PassphraseOptions) -> Result<()> {
+    before_load(&HookEvent::RemoveEntry)?;
+    let (passphrase, mut storage) = load_storage(unlock, PromptOutput::Stdout)?;
+    if storage.entries.remove(entry).is_some() {
+        save_storage(passphrase, &storage)?;
+        after_save(&HookEvent::RemoveEntry, &[entry.to_string()])?;
+    } else {
+        return Err(anyhow!("entry '{}' not found", entry));
     };
 
-    let url = match url {
-        Some(u) => Some(u),
-        None => entry.url,
+    Ok(())
+}
+
+/// Copies an entry, removing the source if `remove_source`. Between stores
+/// the entry is decrypted with the passphrase of the source store and
+/// encrypted with that of the destination store, which is unlocked with
+/// `destination_unlock`; the hooks of both run.
+pub fn copy(
+    source: &EntryLocation,
+    destination: &EntryLocation,
+    remove_source: bool,
+    unlock: &PassphraseOptions,
+    destination_unlock: &PassphraseOptions,
+) -> Result<()> {
+    let event = if remove_source {
+        HookEvent::MoveEntry
+    } else {
+        HookEvent::CopyEntry
+    };
+    let current = paths::store_name()?;
+    let select = |location: &EntryLocation| {
+        paths::select_store(location.store.clone().or_else(|| current.clone()))
+    };
+    let name = match destination.entry.as_str() {
+        "" => source.entry.clone(),
+        name => name.to_string(),
     };
 
-    let password = match no_prompt {
-        true => entry.password,
-        false => rpassword::prompt_password_stdout(&format!("Password for '{}': ", name))?,
+    select(destination);
+    let destination_dir = canonical_storage_dir()?;
+    select(source);
+    let same_store = canonical_storage_dir()? == destination_dir;
+    if same_store && source.entry == name {
+        return Err(anyhow!("source and destination are the same entry"));
+    }
+
+    before_load(&event)?;
+    let (source_passphrase, mut source_storage) = load_storage(unlock, PromptOutput::Stdout)?;
+    let mut entry = source_storage
+        .entries
+        .get(&source.entry)
+        .cloned()
+        .ok_or_else(|| anyhow!("entry '{}' not found", source.entry))?;
+    entry.touch();
+
+    if same_store {
+        if !confirm_overwrite(&source_storage, &name)? {
+            return Ok(());
+        }
+        if remove_source {
+            source_storage.entries.remove(&source.entry);
+        }
+        source_storage.entries.insert(name.clone(), entry);
+        save_storage(source_passphrase, &source_storage)?;
+        return after_save(&event, &[name]);
+    }
+
+    select(destination);
+    before_load(&event)?;
+    let (passphrase, mut storage) = load_storage(destination_unlock, PromptOutput::Stdout)?;
+    if !confirm_overwrite(&storage, &name)? {
+        return Ok(());
+    }
+    storage.entries.insert(name.clone(), entry);
+    save_storage(passphrase, &storage)?;
+    after_save(&event, &[name])?;
+
+    // the source is only changed once the destination is saved
+    if remove_source {
+        select(source);
+        source_storage.entries.remove(&source.entry);
+        save_storage(source_passphrase, &source_storage)?;
+        after_save(&event, std::slice::from_ref(&source.entry))?;
+    }
+    Ok(())
+}
+
+/// Asks whether an existing entry should be overwritten
+fn confirm_overwrite(storage: &Storage, entry: &str) -> Result<bool> {
+    if !storage.entries.contains_key(entry) {
+        return Ok(true);
+    }
+    let overwrite = utilities::read_stdin(&format!(
+        "Entry '{}' already exists. Overwrite (y/N)?",
+        entry
+    ))?;
+    Ok(overwrite.to_uppercase() == "Y")
+}
+
+/// Merges another entries file of the store into it and prints what changed
+pub fn merge(
+    other: &Path,
+    base: Option<&Path>,
+    prefer: Option<Prefer>,
+    unlock: &PassphraseOptions,
+) -> Result<()> {
+    before_load(&HookEvent::MergeEntries)?;
+    let (passphrase, mut storage) = load_storage(unlock, PromptOutput::Stdout)?;
+    let output = PromptOutput::Stdout;
+    let read = |path: &Path, passphrase| read_entries_file(&path.display().to_string(), passphrase);
+    let theirs = read_other_entries(other, &passphrase, unlock, output, read)?;
+    let base = match base {
+        Some(base) => Some(read_other_entries(base, &passphrase, unlock, output, read)?),
+        None => None,
     };
 
-    storage.entries.insert(
-        name,
-        Entry {
-            password,
-            username,
-            url,
+    let summary = merge::merge(
+        base.as_ref().map(|base| &base.entries),
+        &mut storage.entries,
+        &theirs.entries,
+        |conflict| match prefer {
+            Some(policy) => Ok(merge::prefer(policy, conflict)),
+            None => ask_conflict(conflict),
         },
-    );
+    )?;
 
-    save_entries(passphrase, &storage)?;
-    run_hook(&Hook::PostSave, &HookEvent::EditEntry)?;
+    for line in summary.lines() {
+        println!("{}", line);
+    }
 
+    if summary.changed() {
+        save_storage(passphrase, &storage)?;
+        after_save(&HookEvent::MergeEntries, &[])?;
+    }
     Ok(())
 }
 
-pub fn remove(entry: &str, no_keyring: bool) -> Result<()> {
-    run_hook(&Hook::PreLoad, &HookEvent::RemoveEntry)?;
-    let passphrase = utilities::get_passphrase(no_keyring)?;
-    let mut storage = load_entries(passphrase.clone())?;
-    if storage.entries.remove(entry).is_some() {
-        save_entries(passphrase, &storage)?;
-        run_hook(&Hook::PostSave, &HookEvent::RemoveEntry)?;
+/// Reads entries of the store from elsewhere with `read`, asking for the
+/// passphrase of `path` if it isn't the one of the store
+fn read_other_entries(
+    path: &Path,
+    passphrase: &SecretString,
+    unlock: &PassphraseOptions,
+    output: PromptOutput,
+    read: impl Fn(&Path, SecretString) -> Result<Storage>,
+) -> Result<Storage> {
+    match read(path, passphrase.clone()) {
+        Err(e) if utilities::is_wrong_passphrase(&e) => {
+            let prompt = format!("Passphrase of {}: ", path.display());
+            read(path, utilities::prompt_passphrase(&prompt, unlock, output)?)
+        }
+        result => result,
+    }
+}
+
+fn ask_conflict(conflict: &Conflict) -> Result<Side> {
+    let state = |entry: Option<&Entry>| match entry {
+        Some(_) => "changed",
+        None => "removed",
+    };
+    let answer = utilities::read_stdin(&format!(
+        "Entry '{}' was {} in the store and {} in the other file. Keep (o)urs or (t)heirs?",
+        conflict.name,
+        state(conflict.ours),
+        state(conflict.theirs)
+    ))?;
+    match answer.to_lowercase().as_str() {
+        "o" | "ours" => Ok(Side::Ours),
+        "t" | "theirs" => Ok(Side::Theirs),
+        _ => Err(anyhow!("merge aborted, the store is unchanged")),
+    }
+}
+
+/// Checks that the entries file, or each entry file, holds the canonical
+/// document and rewrites it otherwise, unless only checking
+pub fn fmt(check: bool, unlock: &PassphraseOptions) -> Result<()> {
+    before_load(&HookEvent::FormatEntries)?;
+    let (passphrase, _) = load_storage(unlock, PromptOutput::Stdout)?;
+    let store = layout::current()?;
+    let (storage, canonical) = store.check_canonical(passphrase.clone())?;
+    if canonical {
+        println!("The entries {} is canonical", store.kind());
+        return Ok(());
+    }
+    if check {
+        return Err(anyhow!(
+            "the entries {} is not canonical, run `page fmt`",
+            store.kind()
+        ));
+    }
+    save_storage(passphrase, &storage)?;
+    after_save(&HookEvent::FormatEntries, &[])?;
+    println!("Formatted the entries {}", store.kind());
+    Ok(())
+}
+
+/// Lists the backups the backend of the store keeps, which `page merge` can
+/// restore entries from
+pub fn backups() -> Result<()> {
+    for backup in layout::current()?.backups()? {
+        println!("{}", backup);
+    }
+    Ok(())
+}
+
+pub fn env(prefix: &str, format: Option<EnvFormat>, unlock: &PassphraseOptions) -> Result<()> {
+    let format = match format {
+        Some(format) => format,
+        None => config::load()?.env_format.unwrap_or(EnvFormat::Dotenv),
+    };
+    before_load(&HookEvent::ExportEntries)?;
+    let (_, storage) = load_storage(unlock, PromptOutput::Stderr)?;
+
+    let mut names: Vec<&String> = storage
+        .entries
+        .keys()
+        .filter(|name| name.starts_with(prefix))
+        .collect();
+    if names.is_empty() {
+        return Err(anyhow!("no entries found with prefix '{}'", prefix));
+    }
+    names.sort();
+
+    let mut exported: HashMap<String, &String> = HashMap::new();
+    let mut lines = vec![];
+    for name in names {
+        let entry = &storage.entries[name];
+        let variable = match &entry.env_var {
+            Some(v) => v.to_string(),
+            None => exports::mangle(prefix, name),
+        };
+        if let Some(other) = exported.insert(variable.clone(), name) {
+            return Err(anyhow!(
+                "entries '{}' and '{}' both export variable '{}'",
+                other,
+                name,
+                variable
+            ));
+        }
+        lines.push(exports::assignment(&format, &variable, &entry.password)?);
+    }
+
+    for line in lines {
+        println!("{}", line);
+    }
+    Ok(())
+}
+
+pub fn git_credential(operation: &str, unlock: &PassphraseOptions) -> Result<()> {
+    let request = git_credential::Request::parse(io::stdin().lock())?;
+    match operation {
+        "get" => git_credential_get(&request, unlock),
+        "store" => git_credential_store(&request, unlock),
+        "erase" => git_credential_erase(&request, unlock),
+        _ => Ok(()),
+    }
+}
+
+fn git_credential_get(request: &git_credential::Request, unlock: &PassphraseOptions) -> Result<()> {
+    before_load(&HookEvent::ShowEntry)?;
+    let (_, storage) = load_storage(unlock, PromptOutput::Stderr)?;
+
+    // prefer the most specific match, then the first name in alphabetical order
+    let best = storage
+        .entries
+        .iter()
+        .filter_map(|(name, entry)| request.matches(entry).map(|score| (score, name, entry)))
+        .max_by(|a, b| a.0.cmp(&b.0).then(b.1.cmp(a.1)));
+
+    // not printing anything tells git to try the next helper or to prompt
+    if let Some((_, _, entry)) = best {
+        print!("{}", git_credential::response(entry)?);
+    }
+    Ok(())
+}
+
+fn git_credential_store(
+    request: &git_credential::Request,
+    unlock: &PassphraseOptions,
+) -> Result<()> {
+    let password = match (&request.username, &request.password) {
+        (Some(_), Some(password)) => password.to_string(),
+        _ => return Ok(()),
+    };
+
+    before_load(&HookEvent::NewEntry)?;
+    let (passphrase, mut storage) = load_storage(unlock, PromptOutput::Stderr)?;
+
+    let existing = storage
+        .entries
+        .iter()
+        .find(|(_, entry)| request.is_stored_in(entry))
+        .map(|(name, entry)| (name.to_string(), entry.password == password));
+    let name = match existing {
+        Some((_, true)) => return Ok(()),
+        Some((name, false)) => name,
+        None => {
+            let name = request.entry_name()?;
+            if storage.entries.contains_key(&name) {
+                return Err(anyhow!("entry '{}' already exists", name));
+            }
+            name
+        }
+    };
+
+    let entry = storage.entries.entry(name.clone()).or_default();
+    entry.password = password;
+    entry.username = request.username.clone();
+    entry.url = Some(request.url()?);
+    entry.touch();
+
+    save_storage(passphrase, &storage)?;
+    after_save(&HookEvent::NewEntry, &[name])?;
+    Ok(())
+}
+
+fn git_credential_erase(
+    request: &git_credential::Request,
+    unlock: &PassphraseOptions,
+) -> Result<()> {
+    before_load(&HookEvent::RemoveEntry)?;
+    let (passphrase, mut storage) = load_storage(unlock, PromptOutput::Stderr)?;
+
+    // only drop entries holding the rejected password, never unrelated ones
+    let stale: Vec<String> = storage
+        .entries
+        .iter()
+        .filter(|(_, entry)| {
+            request.matches(entry).is_some() && request.password.as_ref() == Some(&entry.password)
+        })
+        .map(|(name, _)| name.to_string())
+        .collect();
+    if stale.is_empty() {
+        return Ok(());
+    }
+    for name in &stale {
+        storage.entries.remove(name);
+    }
+
+    save_storage(passphrase, &storage)?;
+    after_save(&HookEvent::RemoveEntry, &stale)?;
+    Ok(())
+}
+
+/// Merges the revisions of a file holding entries git passes to a merge driver
+/// into `ours`, `path` being the file in the store. Without `prefer`, a
+/// conflict fails the merge so that git reports it. Hooks don't run, as they
+/// may run git themselves.
+pub fn git_merge_driver(
+    base: &Path,
+    ours: &Path,
+    theirs: &Path,
+    path: Option<&Path>,
+    prefer: Option<Prefer>,
+    unlock: &PassphraseOptions,
+) -> Result<()> {
+    let output = PromptOutput::Stderr;
+    let (passphrase, _) = load_storage(unlock, output)?;
+    let store = layout::current()?;
+    let read = |file: &Path, passphrase| store.read_revision(file, path, passphrase);
+    let base = read_other_entries(base, &passphrase, unlock, output, read)?;
+    let mut storage = read_other_entries(ours, &passphrase, unlock, output, read)?;
+    let theirs = read_other_entries(theirs, &passphrase, unlock, output, read)?;
+
+    let summary = merge::merge(
+        Some(&base.entries),
+        &mut storage.entries,
+        &theirs.entries,
+        |conflict| match prefer {
+            Some(policy) => Ok(merge::prefer(policy, conflict)),
+            None => Err(anyhow!(
+                "conflicting changes to '{}', merge the other revision with `page merge`",
+                conflict.name
+            )),
+        },
+    )?;
+    // git passes the driver's standard output on, keep it for the merge result
+    for line in summary.lines() {
+        eprintln!("{}", line);
+    }
+    store.write_revision(ours, passphrase, &storage)
+}
+
+pub fn install_git_merge_driver(prefer: Option<Prefer>) -> Result<()> {
+    let dir = canonical_storage_dir()?;
+    git::install_merge_driver(&dir, prefer)?;
+    println!(
+        "Installed the merge driver in the git config and .gitattributes of {}",
+        dir
+    );
+    Ok(())
+}
+
+/// Prints the entries of a revision of a file holding entries for `git diff`.
+/// A revision which doesn't decrypt with the passphrase of the store is noted
+/// instead, so that `git log -p` goes on.
+pub fn git_diff_textconv(file: &Path, unlock: &PassphraseOptions) -> Result<()> {
+    // a wrong passphrase shows in the revision, without decrypting the whole store first
+    let cached = if unlock.is_unattended() {
+        None
     } else {
-        return Err(anyhow!("entry '{}' not found", entry));
+        agent_load()?
     };
+    let passphrase = match cached {
+        Some((passphrase, _)) => passphrase,
+        None => utilities::read_passphrase(unlock, PromptOutput::Stderr)?.0,
+    };
+    match layout::current()?.read_revision(file, None, passphrase.clone()) {
+        Ok(storage) => {
+            let key_file = match StoreSettings::load()?.key_file() {
+                Some(path) => Some(utilities::read_key_file(&path)?),
+                None => None,
+            };
+            let key = textconv::FingerprintKey::derive(&passphrase, key_file.as_ref())?;
+            print!("{}", textconv::listing(&storage, &key))
+        }
+        Err(e) if utilities::is_wrong_passphrase(&e) => {
+            println!("# encrypted with another passphrase")
+        }
+        Err(e) => return Err(e),
+    }
+    Ok(())
+}
 
+pub fn install_git_diff_textconv() -> Result<()> {
+    let dir = canonical_storage_dir()?;
+    git::install_textconv(&dir)?;
+    println!(
+        "Installed the diff driver in the git config and .gitattributes of {}",
+        dir
+    );
+    Ok(())
+}
+
+pub fn docker_credential(
+    operation: DockerCredentialOperation,
+    folder: &str,
+    unlock: &PassphraseOptions,
+) -> Result<()> {
+    // docker sends a single line: a server url, or credentials as JSON for `store`
+    let mut input = String::new();
+    if !matches!(operation, DockerCredentialOperation::List) {
+        io::stdin().read_line(&mut input)?;
+    }
+    let input = input.trim();
+
+    match operation {
+        DockerCredentialOperation::Get => {
+            before_load(&HookEvent::ShowEntry)?;
+            let (_, storage) = load_storage(unlock, PromptOutput::Stderr)?;
+
+            let found = storage.entries.iter().find(|(name, entry)| {
+                docker_credential::in_folder(folder, name)
+                    && docker_credential::matches(input, entry)
+            });
+            match found {
+                Some((_, entry)) => {
+                    let credentials = docker_credential::Credentials {
+                        server_url: input.to_string(),
+                        username: entry.username.clone().unwrap_or_default(),
+                        secret: entry.password.clone(),
+                    };
+                    println!("{}", serde_json::to_string(&credentials)?);
+                }
+                None => {
+                    // docker reads errors from stdout
+                    println!("{}", docker_credential::NOT_FOUND);
+                    return Err(anyhow!(docker_credential::NOT_FOUND));
+                }
+            }
+        }
+        DockerCredentialOperation::Store => {
+            let credentials: docker_credential::Credentials = serde_json::from_str(input)?;
+            before_load(&HookEvent::NewEntry)?;
+            let (passphrase, mut storage) = load_storage(unlock, PromptOutput::Stderr)?;
+
+            let name = docker_credential::entry_name(folder, &credentials.server_url);
+            let entry = storage.entries.entry(name.clone()).or_default();
+            entry.password = credentials.secret;
+            entry.username = Some(credentials.username);
+            entry.url = Some(credentials.server_url);
+            entry.touch();
+
+            save_storage(passphrase, &storage)?;
+            after_save(&HookEvent::NewEntry, &[name])?;
+        }
+        DockerCredentialOperation::Erase => {
+            before_load(&HookEvent::RemoveEntry)?;
+            let (passphrase, mut storage) = load_storage(unlock, PromptOutput::Stderr)?;
+
+            let names: Vec<String> = storage
+                .entries
+                .iter()
+                .filter(|(name, entry)| {
+                    docker_credential::in_folder(folder, name)
+                        && docker_credential::matches(input, entry)
+                })
+                .map(|(name, _)| name.to_string())
+                .collect();
+            if names.is_empty() {
+                println!("{}", docker_credential::NOT_FOUND);
+                return Err(anyhow!(docker_credential::NOT_FOUND));
+            }
+            for name in &names {
+                storage.entries.remove(name);
+            }
+
+            save_storage(passphrase, &storage)?;
+            after_save(&HookEvent::RemoveEntry, &names)?;
+        }
+        DockerCredentialOperation::List => {
+            before_load(&HookEvent::ListEntries)?;
+            let (_, storage) = load_storage(unlock, PromptOutput::Stderr)?;
+
+            let registries: BTreeMap<&String, String> = storage
+                .entries
+                .iter()
+                .filter(|(name, _)| docker_credential::in_folder(folder, name))
+                .filter_map(|(_, entry)| {
+                    let username = entry.username.clone().unwrap_or_default();
+                    entry.url.as_ref().map(|url| (url, username))
+                })
+                .collect();
+            println!("{}", serde_json::to_string(&registries)?);
+        }
+    }
+    Ok(())
+}
+
+pub fn aws_credentials(entry: &str, fields: &AwsFields, unlock: &PassphraseOptions) -> Result<()> {
+    before_load(&HookEvent::ShowEntry)?;
+    let (_, storage) = load_storage(unlock, PromptOutput::Stderr)?;
+
+    let entry = storage
+        .entries
+        .get(entry)
+        .ok_or_else(|| anyhow!("entry '{}' not found", entry))?;
+    let credentials = aws::ProcessCredentials::from_entry(entry, fields)?;
+    println!("{}", serde_json::to_string(&credentials)?);
+    Ok(())
+}
+
+pub fn askpass(prompt: &str, unlock: &PassphraseOptions) -> Result<()> {
+    let target = askpass::Rules::load()?.resolve(prompt)?;
+
+    before_load(&HookEvent::ShowEntry)?;
+    let (_, storage) = load_storage(unlock, PromptOutput::Stderr)?;
+
+    let entry = storage
+        .entries
+        .get(&target.entry)
+        .ok_or_else(|| anyhow!("entry '{}' not found", target.entry))?;
+    let secret = match &target.field {
+        Some(field) => entry
+            .fields
+            .get(field)
+            .ok_or_else(|| anyhow!("entry has no field '{}'", field))?,
+        None => &entry.password,
+    };
+    println!("{}", secret);
     Ok(())
 }
 
 pub fn info() -> Result<()> {
-    let storage_path = entries_file()?;
-    if fs::metadata(storage_path.clone()).is_ok() {
-        println!("Storage file: {}", storage_path);
+    if let Some(name) = paths::store_name()? {
+        println!("Store: {}", name);
+    }
+    let store = layout::current()?;
+    if store.exists()? {
+        println!("Storage {}: {}", store.kind(), store.path());
     } else {
-        println!("Storage file doesn't exist yet, run `passge init` to create it");
+        println!(
+            "Storage {} doesn't exist yet, run `passge init` to create it",
+            store.kind()
+        );
+    }
+
+    if let Some(log_n) = store.scrypt_work_factor()? {
+        println!("Scrypt work factor: {}", log_n);
+    }
+    if let Some(work_factor) = StoreSettings::load()?.work_factor {
+        println!("Configured scrypt work factor: {}", work_factor);
     }
 
     let hooks_dir = hooks_dir()?;
@@ -210,12 +864,196 @@ pub fn info() -> Result<()> {
     } else {
         println!("Hooks directory does not exist yet: {}", hooks_dir);
     }
+
+    #[cfg(unix)]
+    if let Some(stores) = agent::status()? {
+        println!("Agent: running, {} unlocked store(s)", stores.len());
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+pub fn agent(idle_timeout: u64, timeout: u64, foreground: bool) -> Result<()> {
+    let timeouts = agent::Timeouts {
+        idle: Duration::from_secs(idle_timeout),
+        absolute: Duration::from_secs(timeout),
+    };
+    agent::run(timeouts, foreground)
+}
+
+#[cfg(not(unix))]
+pub fn agent(_idle_timeout: u64, _timeout: u64, _foreground: bool) -> Result<()> {
+    Err(anyhow!("the agent is only supported on Unix"))
+}
+
+pub fn unlock(expiry: u64, format: EnvFormat, unlock: &PassphraseOptions) -> Result<()> {
+    // loading the store checks the passphrase
+    let (passphrase, _) = load_storage(unlock, PromptOutput::Stderr)?;
+    let token = session::create(
+        &canonical_storage_dir()?,
+        &passphrase,
+        Duration::from_secs(expiry),
+    )?;
+    println!(
+        "{}",
+        exports::assignment(&format, session::SESSION_VARIABLE, token.expose_secret())?
+    );
+    Ok(())
+}
+
+pub fn lock() -> Result<()> {
+    let sessions = session::lock()?;
+    if sessions > 0 {
+        println!("Invalidated {} session(s)", sessions);
+    }
+    lock_agent()
+}
+
+#[cfg(unix)]
+fn lock_agent() -> Result<()> {
+    if agent::lock()? {
+        println!("Agent locked");
+    } else {
+        println!("Agent is not running");
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn lock_agent() -> Result<()> {
+    Ok(())
+}
+
+pub fn git_init(remote: Option<&str>) -> Result<()> {
+    if !layout::current()?.exists()? {
+        return Err(anyhow!("storage not initialized, run `page init`"));
+    }
+    let dir = canonical_storage_dir()?;
+    git::init(&dir, remote)?;
+    println!("Initialized git repository in {}", dir);
+    Ok(())
+}
+
+pub fn git_sync() -> Result<()> {
+    let dir = canonical_storage_dir()?;
+    git::sync(&dir)?;
+    if let Some(upstream) = git::upstream(&dir) {
+        println!("Synced with {}", upstream);
+    }
+    Ok(())
+}
+
+pub fn git_log(args: Vec<String>) -> Result<()> {
+    let mut log = vec!["log".to_string()];
+    log.extend(args);
+    git::run(&canonical_storage_dir()?, &log)
+}
+
+pub fn git_status() -> Result<()> {
+    git::status(&canonical_storage_dir()?)
+}
+
+pub fn store_add(name: &str, path: &Path, default: bool) -> Result<()> {
+    if name.is_empty() || name.contains(':') {
+        return Err(anyhow!("store names can't be empty or contain ':'"));
+    }
+    let mut config = UserConfig::load()?;
+    if config.stores.contains_key(name) {
+        return Err(anyhow!("store '{}' already exists", name));
+    }
+    let path = match fs::canonicalize(path) {
+        Ok(path) => path,
+        Err(_) => std::env::current_dir()?.join(path),
+    };
+    println!("Added store '{}' in {}", name, path.display());
+    config.stores.insert(name.to_string(), path);
+    if default {
+        config.default_store = Some(name.to_string());
+    }
+    config.save()
+}
+
+pub fn store_remove(name: &str) -> Result<()> {
+    let mut config = UserConfig::load()?;
+    if config.stores.remove(name).is_none() {
+        return Err(anyhow!("unknown store '{}', see `page store list`", name));
+    }
+    if config.default_store.as_deref() == Some(name) {
+        config.default_store = None;
+    }
+    config.save()
+}
+
+pub fn store_list() -> Result<()> {
+    let config = UserConfig::load()?;
+    let current = paths::store_name()?;
+    for (name, path) in &config.stores {
+        let marker = if current.as_ref() == Some(name) {
+            '*'
+        } else {
+            ' '
+        };
+        println!("{} {}\t{}", marker, name, path.display());
+    }
+    Ok(())
+}
+
+pub fn store_default(name: &str) -> Result<()> {
+    let mut config = UserConfig::load()?;
+    if !config.stores.contains_key(name) {
+        return Err(anyhow!("unknown store '{}', see `page store list`", name));
+    }
+    config.default_store = Some(name.to_string());
+    config.save()
+}
+
+pub fn config_get(key: &str) -> Result<()> {
+    match config::get(key)? {
+        Some((toml::Value::String(value), _)) => println!("{}", value),
+        Some((value, _)) => println!("{}", value),
+        None => return Err(anyhow!("'{}' is not set", key)),
+    }
     Ok(())
 }
 
-pub fn keyring_check() -> Result<()> {
-    let username = &whoami::username();
-    let keyring = utilities::new_keyring(username);
+pub fn config_set(key: &str, value: Option<&str>, user: bool) -> Result<()> {
+    let scope = if user { Scope::User } else { Scope::Store };
+    if let Scope::Store = scope {
+        if fs::metadata(storage_dir()?).is_err() {
+            return Err(anyhow!(
+                "storage not initialized, run `page init` or use --user"
+            ));
+        }
+    }
+    config::set(scope, key, value)
+}
+
+pub fn config_list() -> Result<()> {
+    for scope in [Scope::Store, Scope::User] {
+        for (key, value) in config::list(scope)? {
+            println!("{}\t{} = {}", scope.name(), key, value);
+        }
+    }
+    Ok(())
+}
+
+pub fn keyring_check(all: bool) -> Result<()> {
+    if all {
+        let accounts = utilities::keyring_accounts()?;
+        if accounts.is_empty() {
+            println!("No store has a passphrase in the keyring");
+        }
+        for account in accounts {
+            match utilities::new_keyring(&account).get_password() {
+                Ok(_) => println!("{}: ok", account),
+                Err(_) => println!("{}: failed to access password in keyring", account),
+            }
+        }
+        return Ok(());
+    }
+
+    let account = utilities::keyring_account()?;
+    let keyring = utilities::new_keyring(&account);
     if keyring.get_password().is_err() {
         return Err(anyhow!("Failed to access password in keyring"));
     }
@@ -223,15 +1061,179 @@ pub fn keyring_check() -> Result<()> {
     Ok(())
 }
 
-pub fn keyring_forget() -> Result<()> {
-    let username = &whoami::username();
-    let keyring = utilities::new_keyring(username);
-    if keyring.delete_password().is_err() {
-        return Err(anyhow!("Failed to delete password from keyring"));
+pub fn keyring_set(unlock: &PassphraseOptions) -> Result<()> {
+    let passphrase = match utilities::read_passphrase(unlock, PromptOutput::Stdout)? {
+        (passphrase, PassphraseSource::Unattended) => passphrase,
+        _ => utilities::prompt_passphrase("Enter passphrase: ", unlock, PromptOutput::Stdout)?,
+    };
+    // only store a passphrase which decrypts the store
+    if layout::current()?.exists()? {
+        load_entries(passphrase.clone())?;
     }
+    utilities::store_keyring_passphrase(&passphrase)?;
+    println!("Stored passphrase in keyring");
     Ok(())
 }
 
+pub fn keyring_forget(all: bool) -> Result<()> {
+    if all {
+        for account in utilities::keyring_accounts()? {
+            utilities::forget_keyring_account(&account)?;
+        }
+        let _ = utilities::new_keyring(&utilities::legacy_keyring_account()).delete_password();
+        return Ok(());
+    }
+
+    utilities::forget_keyring_account(&utilities::keyring_account()?)
+}
+
+/// Prepares loading the store: pulls changes if configured, then runs the
+/// pre_load hook
+fn before_load(event: &HookEvent) -> Result<()> {
+    git::pull_before_load()?;
+    run_hook(&Hook::PreLoad, event)
+}
+
+/// Finishes saving the store: runs the post_save hook, then commits the
+/// change if configured
+fn after_save(event: &HookEvent, entries: &[String]) -> Result<()> {
+    run_hook(&Hook::PostSave, event)?;
+    git::commit_after_save(event, entries)
+}
+
+/// Loads the store from the agent if it is unlocked there, or decrypts it
+/// and hands it to the agent otherwise
+fn load_storage(
+    unlock: &PassphraseOptions,
+    output: PromptOutput,
+) -> Result<(SecretString, Storage)> {
+    // an explicitly given passphrase source takes precedence
+    if !unlock.is_unattended() {
+        if let Some(cached) = agent_load()? {
+            return Ok(cached);
+        }
+    }
+    let (passphrase, storage) = unlock_storage(unlock, output)?;
+    agent_unlock(&passphrase, &storage)?;
+    Ok((passphrase, storage))
+}
+
+/// Saves the store, keeping the agent's copy up to date
+fn save_storage(passphrase: SecretString, storage: &Storage) -> Result<()> {
+    save_entries(passphrase, storage)?;
+    agent_save(storage)
+}
+
+/// Gets the passphrase and decrypts the store. If a passphrase from the keyring
+/// doesn't decrypt it (e.g. after it was changed on another machine), the user is
+/// asked again and the keyring is updated.
+fn unlock_storage(
+    unlock: &PassphraseOptions,
+    output: PromptOutput,
+) -> Result<(SecretString, Storage)> {
+    let (passphrase, source) = utilities::read_passphrase(unlock, output)?;
+    let storage = match load_entries(passphrase.clone()) {
+        Ok(storage) => storage,
+        Err(e)
+            if utilities::is_wrong_passphrase(&e)
+                && matches!(
+                    source,
+                    PassphraseSource::Keyring | PassphraseSource::LegacyKeyring
+                ) =>
+        {
+            eprintln!("The passphrase in the keyring doesn't decrypt the store");
+            let passphrase = utilities::prompt_passphrase("Enter passphrase: ", unlock, output)?;
+            let storage = load_entries(passphrase.clone())?;
+            utilities::store_keyring_passphrase(&passphrase)?;
+            return Ok((passphrase, storage));
+        }
+        Err(e) => return Err(e),
+    };
+
+    match source {
+        PassphraseSource::Prompt if !unlock.no_keyring => {
+            utilities::store_keyring_passphrase(&passphrase)?
+        }
+        PassphraseSource::LegacyKeyring => utilities::migrate_legacy_keyring(&passphrase)?,
+        _ => {}
+    }
+    Ok((passphrase, storage))
+}
+
+/// The name of the current store in the agent: its canonical storage folder
+#[cfg(unix)]
+fn agent_store() -> Result<String> {
+    canonical_storage_dir()
+}
+
+#[cfg(unix)]
+fn agent_load() -> Result<Option<(SecretString, Storage)>> {
+    match agent::load(&agent_store()?)? {
+        Some((passphrase, plaintext, version)) => {
+            let mut storage = parse_entries(&plaintext)?;
+            storage.version = Some(version);
+            Ok(Some((passphrase, storage)))
+        }
+        None => Ok(None),
+    }
+}
+
+#[cfg(not(unix))]
+fn agent_load() -> Result<Option<(SecretString, Storage)>> {
+    Ok(None)
+}
+
+#[cfg(unix)]
+fn agent_unlock(passphrase: &SecretString, storage: &Storage) -> Result<()> {
+    agent::unlock(&agent_store()?, passphrase, serialize_entries(storage)?)
+}
+
+#[cfg(not(unix))]
+fn agent_unlock(_passphrase: &SecretString, _storage: &Storage) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn agent_save(storage: &Storage) -> Result<()> {
+    agent::save(&agent_store()?, serialize_entries(storage)?)
+}
+
+#[cfg(not(unix))]
+fn agent_save(_storage: &Storage) -> Result<()> {
+    Ok(())
+}
+
+/// Applies the attributes given on the command line to an entry, asking for
+/// the values of fields given without one
+fn update_entry(entry: &mut Entry, options: EntryOptions) -> Result<()> {
+    if let Some(username) = options.username {
+        entry.username = Some(username);
+    }
+    if let Some(url) = options.url {
+        entry.url = Some(url);
+    }
+    if let Some(env_var) = options.env_var {
+        entry.env_var = Some(env_var);
+    }
+    for (name, value) in options.fields {
+        let value = match value {
+            Some(value) => value,
+            None => rpassword::prompt_password_stdout(&format!("Value of field '{}': ", name))?,
+        };
+        entry.fields.insert(name, value);
+    }
+    Ok(())
+}
+
+fn check_env_var(env_var: &Option<String>) -> Result<()> {
+    match env_var {
+        Some(v) if !exports::is_valid_variable(v) => {
+            Err(anyhow!("'{}' is not a valid environment variable name", v))
+        }
+        _ => Ok(()),
+    }
+}
+
 pub fn shell_completion(shell: cli::Shell) {
     let mut cmd = cli::Cli::command();
     let bin_name = cmd
@@ -0,0 +1,260 @@
+use crate::cli::EnvFormat;
+use crate::paths::user_config_file;
+use crate::settings::StoreSettings;
+use crate::utilities::MAX_WORK_FACTOR;
+use anyhow::{anyhow, Context, Result};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Settings which can be made in the user config and in the config of a
+/// store. Command line options and environment variables take precedence
+/// over the store config, which takes precedence over the user config.
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+pub struct Config {
+    /// Whether the passphrase is stored in the keyring, see `--no-keyring`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keyring: Option<bool>,
+
+    /// The pinentry program to ask for the passphrase with, see `--pinentry`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pinentry: Option<String>,
+
+    /// Seconds after which a copied value is cleared from the clipboard, 0 to keep it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub clipboard_timeout: Option<u64>,
+
+    /// The default output format of `page env`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub env_format: Option<EnvFormat>,
+
+    /// Whether hooks are run
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hooks: Option<bool>,
+
+    /// The hooks directory, relative to the storage folder unless absolute
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hooks_dir: Option<String>,
+
+    /// Whether changes are committed to the store's git repository and pushed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub git_auto_commit: Option<bool>,
+
+    /// Whether changes are pulled from the store's git repository before loading
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub git_auto_pull: Option<bool>,
+}
+
+/// Keys of `Config`
+pub const KEYS: &[&str] = &[
+    "keyring",
+    "pinentry",
+    "clipboard_timeout",
+    "env_format",
+    "hooks",
+    "hooks_dir",
+    "git_auto_commit",
+    "git_auto_pull",
+];
+
+/// Keys of `StoreSettings` which can only be set for a store
+pub const STORE_KEYS: &[&str] = &["work_factor", "armor"];
+
+/// Keys of `UserConfig` which can only be set for the user
+pub const USER_KEYS: &[&str] = &["default_store"];
+
+/// The user config, with settings which only make sense for the user
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct UserConfig {
+    #[serde(flatten)]
+    pub config: Config,
+
+    /// The store used unless another one is selected
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default_store: Option<String>,
+
+    /// Named stores and their storage folders, see `page store`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub stores: BTreeMap<String, PathBuf>,
+}
+
+impl UserConfig {
+    /// Loads the user config, the defaults if there is none
+    pub fn load() -> Result<UserConfig> {
+        let path = user_config_file()?;
+        match fs::read_to_string(&path) {
+            Ok(config) => {
+                toml::from_str(&config).with_context(|| format!("Failed to parse {}", path))
+            }
+            Err(_) => Ok(UserConfig::default()),
+        }
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let path = user_config_file()?;
+        if let Some(dir) = Path::new(&path).parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, toml::to_string(self)?)?;
+        Ok(())
+    }
+}
+
+impl Config {
+    /// Returns the settings of `self`, falling back to those of `other`
+    fn or(self, other: Config) -> Config {
+        Config {
+            keyring: self.keyring.or(other.keyring),
+            pinentry: self.pinentry.or(other.pinentry),
+            clipboard_timeout: self.clipboard_timeout.or(other.clipboard_timeout),
+            env_format: self.env_format.or(other.env_format),
+            hooks: self.hooks.or(other.hooks),
+            hooks_dir: self.hooks_dir.or(other.hooks_dir),
+            git_auto_commit: self.git_auto_commit.or(other.git_auto_commit),
+            git_auto_pull: self.git_auto_pull.or(other.git_auto_pull),
+        }
+    }
+}
+
+/// Loads the effective config: the store config, falling back to the user config
+pub fn load() -> Result<Config> {
+    Ok(StoreSettings::load()?.config.or(UserConfig::load()?.config))
+}
+
+/// Where a setting is made
+#[derive(Clone, Copy)]
+pub enum Scope {
+    User,
+    Store,
+}
+
+impl Scope {
+    pub fn name(&self) -> &str {
+        match self {
+            Scope::User => "user",
+            Scope::Store => "store",
+        }
+    }
+}
+
+fn to_table<T: Serialize>(value: &T) -> Result<toml::value::Table> {
+    match toml::Value::try_from(value)? {
+        toml::Value::Table(table) => Ok(table),
+        _ => Err(anyhow!("config is not a table")),
+    }
+}
+
+/// Returns the settings made in a scope
+pub fn list(scope: Scope) -> Result<toml::value::Table> {
+    match scope {
+        Scope::User => to_table(&UserConfig::load()?),
+        Scope::Store => to_table(&StoreSettings::load()?),
+    }
+}
+
+fn check_key(key: &str, scope: Scope) -> Result<()> {
+    if KEYS.contains(&key) {
+        Ok(())
+    } else if STORE_KEYS.contains(&key) {
+        match scope {
+            Scope::Store => Ok(()),
+            Scope::User => Err(anyhow!("'{}' can only be set for a store", key)),
+        }
+    } else if USER_KEYS.contains(&key) {
+        match scope {
+            Scope::User => Ok(()),
+            Scope::Store => Err(anyhow!(
+                "'{}' can only be set for the user, use --user",
+                key
+            )),
+        }
+    } else if key == "key_file" {
+        Err(anyhow!(
+            "the key file can only be set up with `page init --key-file`"
+        ))
+    } else if key == "stores" {
+        Err(anyhow!("stores are managed with `page store`"))
+    } else {
+        Err(anyhow!("unknown config key '{}'", key))
+    }
+}
+
+/// Returns the value of a setting and where it is made, store before user
+pub fn get(key: &str) -> Result<Option<(toml::Value, Scope)>> {
+    let known = [KEYS, STORE_KEYS, USER_KEYS, &["key_file"]];
+    if !known.iter().any(|keys| keys.contains(&key)) {
+        return Err(anyhow!("unknown config key '{}'", key));
+    }
+    for scope in [Scope::Store, Scope::User] {
+        if let Some(value) = list(scope)?.remove(key) {
+            return Ok(Some((value, scope)));
+        }
+    }
+    Ok(None)
+}
+
+/// Interpretations of a value given on the command line, booleans and
+/// numbers are tried before strings
+fn parse_value(value: &str) -> Vec<toml::Value> {
+    let mut values = vec![];
+    if let Ok(b) = value.parse() {
+        values.push(toml::Value::Boolean(b));
+    }
+    if let Ok(i) = value.parse() {
+        values.push(toml::Value::Integer(i));
+    }
+    values.push(toml::Value::String(value.to_string()));
+    values
+}
+
+/// Replaces a setting in a config table and parses the result, trying each
+/// interpretation of the value
+fn update<T: Serialize + DeserializeOwned>(
+    config: &T,
+    key: &str,
+    value: Option<&str>,
+) -> Result<T> {
+    let table = to_table(config)?;
+    let values = match value {
+        Some(value) => parse_value(value).into_iter().map(Some).collect(),
+        None => vec![None],
+    };
+    for value in values {
+        let mut table = table.clone();
+        match value {
+            Some(value) => table.insert(key.to_string(), value),
+            None => table.remove(key),
+        };
+        if let Ok(config) = toml::Value::Table(table).try_into() {
+            return Ok(config);
+        }
+    }
+    Err(anyhow!("invalid value for '{}'", key))
+}
+
+/// Changes a setting, `None` removes it
+pub fn set(scope: Scope, key: &str, value: Option<&str>) -> Result<()> {
+    check_key(key, scope)?;
+    match scope {
+        Scope::User => {
+            let config = update(&UserConfig::load()?, key, value)?;
+            if let Some(name) = &config.default_store {
+                if !config.stores.contains_key(name) {
+                    return Err(anyhow!("unknown store '{}', see `page store list`", name));
+                }
+            }
+            config.save()
+        }
+        Scope::Store => {
+            let settings = update(&StoreSettings::load()?, key, value)?;
+            if let Some(log_n) = settings.work_factor {
+                if !(1..=MAX_WORK_FACTOR).contains(&log_n) {
+                    return Err(anyhow!("invalid value for 'work_factor'"));
+                }
+            }
+            settings.save()
+        }
+    }
+}
@@ -0,0 +1,47 @@
+use crate::entries::Entry;
+use serde::{Deserialize, Serialize};
+
+/// The message docker expects on stdout when a helper has no credentials
+pub const NOT_FOUND: &str = "credentials not found in native keychain";
+
+/// Registry credentials as exchanged with docker, see
+/// https://github.com/docker/docker-credential-helpers
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Credentials {
+    #[serde(rename = "ServerURL")]
+    pub server_url: String,
+    #[serde(rename = "Username")]
+    pub username: String,
+    #[serde(rename = "Secret")]
+    pub secret: String,
+}
+
+/// Strips the scheme and trailing slashes, so that `https://index.docker.io/v1/`
+/// and `index.docker.io/v1` refer to the same registry
+fn normalize(server_url: &str) -> &str {
+    let url = match server_url.split_once("://") {
+        Some((_, rest)) => rest,
+        None => server_url,
+    };
+    url.trim_end_matches('/')
+}
+
+/// The name of the entry storing the credentials for a registry
+pub fn entry_name(folder: &str, server_url: &str) -> String {
+    format!("{}/{}", folder.trim_end_matches('/'), normalize(server_url))
+}
+
+/// Whether an entry is inside the folder used for docker credentials
+pub fn in_folder(folder: &str, name: &str) -> bool {
+    name.strip_prefix(folder.trim_end_matches('/'))
+        .map(|rest| rest.starts_with('/'))
+        .unwrap_or(false)
+}
+
+/// Whether an entry holds the credentials of the given registry
+pub fn matches(server_url: &str, entry: &Entry) -> bool {
+    match &entry.url {
+        Some(url) => normalize(url) == normalize(server_url),
+        None => false,
+    }
+}
@@ -1,54 +1,239 @@
-use crate::paths::entries_file;
-use crate::utilities::{decrypt, encrypt};
+use crate::layout;
+use crate::settings::StoreSettings;
+use crate::utilities::{
+    armor, dearmor, decrypt, decrypt_with_key, encrypt, encrypt_to_key, now, read_key_file,
+    MAX_WORK_FACTOR, MAX_WORK_FACTOR_VARIABLE,
+};
 use age::secrecy::SecretString;
 use anyhow::{anyhow, Context, Result};
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashMap,
-    fs,
+    collections::BTreeMap,
     fs::File,
     io::{BufReader, Read, Write},
 };
 
-#[derive(Debug, Deserialize, Serialize)]
+/// The entries of a store. Entries and their fields are kept sorted by name,
+/// so that the same entries always serialize to the same document.
+#[derive(Debug, Default, Deserialize, Serialize)]
 pub struct Storage {
     #[serde(flatten)]
-    pub entries: HashMap<String, Entry>,
+    pub entries: BTreeMap<String, Entry>,
+    /// The version of the store the entries were loaded from, saving fails
+    /// if the store was changed since. `None` for a new store.
+    #[serde(skip)]
+    pub version: Option<String>,
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
 pub struct Entry {
     pub password: String,
     pub username: Option<String>,
     pub url: Option<String>,
+    pub env_var: Option<String>,
+    /// When the entry was last changed, in seconds since the Unix epoch, used
+    /// to merge stores. Missing for entries of older versions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub modified: Option<u64>,
+    /// Custom fields, e.g. the AWS access key id used by `page aws-credentials`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub fields: BTreeMap<String, String>,
+}
+
+impl Entry {
+    /// Records that the entry was changed
+    pub fn touch(&mut self) {
+        self.modified = Some(now());
+    }
+
+    /// Whether both entries hold the same values, regardless of when they
+    /// were changed
+    pub fn same_values(&self, other: &Entry) -> bool {
+        self.password == other.password
+            && self.username == other.username
+            && self.url == other.url
+            && self.env_var == other.env_var
+            && self.fields == other.fields
+    }
 }
 
+/// Decrypts the entries of the current store
 pub fn load_entries(passphrase: SecretString) -> Result<Storage> {
+    let store = layout::current()?;
+    if !store.exists()? {
+        return Err(anyhow!("storage not initialized, run `page init`"));
+    }
+    store.load(passphrase)
+}
+
+/// Reads an entries file of the current store, e.g. a copy of it from another
+/// device
+pub fn read_entries_file(path: &str, passphrase: SecretString) -> Result<Storage> {
+    read_entries_file_with(path, passphrase, &StoreSettings::load()?)
+}
+
+/// Reads an entries file encrypted with the given settings
+pub fn read_entries_file_with(
+    path: &str,
+    passphrase: SecretString,
+    settings: &StoreSettings,
+) -> Result<Storage> {
+    parse_document(&read_entries_document_with(path, passphrase, settings)?)
+}
+
+/// Parses the document of an entries file, which is empty for a store
+/// created by older versions
+pub fn parse_document(document: &str) -> Result<Storage> {
+    if document.is_empty() {
+        Ok(Storage::default())
+    } else {
+        parse_entries(document)
+    }
+}
+
+/// Decrypts an entries file encrypted with the given settings into the
+/// document it holds
+pub fn read_entries_document_with(
+    path: &str,
+    passphrase: SecretString,
+    settings: &StoreSettings,
+) -> Result<String> {
     let mut encrypted: Vec<u8> = vec![];
-    let entries_file_path = entries_file()?;
-    let file = match fs::metadata(&entries_file_path) {
-        Ok(_) => File::open(&entries_file_path)?,
-        Err(_) => return Err(anyhow!("storage not initialized, run `page init`")),
-    };
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path))?;
     let mut buf = BufReader::new(file);
     buf.read_to_end(&mut encrypted)?;
+    decrypt_document(&encrypted, passphrase, settings)
+        .with_context(|| format!("Failed to decrypt entries file {}", path))
+}
+
+/// Decrypts the contents of an entries file into the document it holds
+pub fn decrypt_document(
+    encrypted: &[u8],
+    passphrase: SecretString,
+    settings: &StoreSettings,
+) -> Result<String> {
     if let 0 = encrypted.len() {
-        Ok(Storage {
-            entries: HashMap::new(),
-        })
+        return Ok(String::new());
+    }
+    let decrypted = decrypt_entries(encrypted, passphrase, settings)?;
+    Ok(String::from_utf8(decrypted)?)
+}
+
+/// Encrypts entries into the contents of an entries file
+pub fn encrypt_document(
+    storage: &Storage,
+    passphrase: SecretString,
+    settings: &StoreSettings,
+) -> Result<Vec<u8>> {
+    encrypt_entries(serialize_entries(storage)?.as_bytes(), passphrase, settings)
+}
+
+/// Work factors above this are unusual unless configured for the store,
+/// 2^22 needs 4 GiB of memory
+const UNUSUAL_WORK_FACTOR: u8 = 22;
+
+/// The highest scrypt work factor an entries file is decrypted with: the one
+/// configured for the store, or an unusual one if none is, unless raised with
+/// `PAGE_MAX_WORK_FACTOR`
+fn max_work_factor(settings: &StoreSettings) -> Result<u8> {
+    let expected = settings.work_factor.unwrap_or(UNUSUAL_WORK_FACTOR);
+    match std::env::var(MAX_WORK_FACTOR_VARIABLE) {
+        Ok(value) => match value.parse::<u8>() {
+            Ok(log_n) if log_n <= MAX_WORK_FACTOR => Ok(log_n.max(expected)),
+            _ => Err(anyhow!(
+                "invalid value for {}, expected at most {}",
+                MAX_WORK_FACTOR_VARIABLE,
+                MAX_WORK_FACTOR
+            )),
+        },
+        Err(_) => Ok(expected),
+    }
+}
+
+/// Decrypts the contents of an entries file. With a key file, the entries
+/// are encrypted to the key file first and then with the passphrase.
+pub fn decrypt_entries(
+    encrypted: &[u8],
+    passphrase: SecretString,
+    settings: &StoreSettings,
+) -> Result<Vec<u8>> {
+    let encrypted = dearmor(encrypted)?;
+    let decrypted = decrypt(&encrypted, passphrase, max_work_factor(settings)?)?;
+    match settings.key_file() {
+        Some(path) => {
+            let identity = read_key_file(&path)?;
+            decrypt_with_key(&decrypted, &identity)
+                .map_err(|_| anyhow!("the key file {} doesn't match the store", path.display()))
+        }
+        None => Ok(decrypted),
+    }
+}
+
+/// Encrypts the contents of an entries file, see `decrypt_entries`
+pub fn encrypt_entries(
+    plaintext: &[u8],
+    passphrase: SecretString,
+    settings: &StoreSettings,
+) -> Result<Vec<u8>> {
+    let encrypted = match settings.key_file() {
+        Some(path) => {
+            let identity = read_key_file(&path)?;
+            let inner = encrypt_to_key(plaintext, &identity.to_public())?;
+            encrypt(&inner, passphrase, settings.work_factor)?
+        }
+        None => encrypt(plaintext, passphrase, settings.work_factor)?,
+    };
+    if settings.armor {
+        armor(&encrypted)
     } else {
-        let decrypted = decrypt(&encrypted, passphrase)
-            .with_context(|| format!("Failed to decrypt entries file {}", entries_file_path))?;
-        let decrypted = String::from_utf8(decrypted)?;
-        let decrypted: Storage = toml::from_str(&decrypted)?;
-        Ok(decrypted)
+        Ok(encrypted)
+    }
+}
+
+pub fn parse_entries(plaintext: &str) -> Result<Storage> {
+    Ok(toml::from_str(plaintext)?)
+}
+
+pub fn serialize_entries(storage: &Storage) -> Result<String> {
+    Ok(toml::to_string(storage)?)
+}
+
+/// Parses an entries document and tells whether it is in the canonical form
+/// `serialize_entries` writes. Content unknown to `Storage` is an error, as
+/// saving would drop it.
+pub fn check_canonical(document: &str) -> Result<(Storage, bool)> {
+    let storage = parse_entries(document)?;
+    match canonical_form(document, &storage)? {
+        Some(canonical) => Ok((storage, canonical)),
+        None => Err(anyhow!(
+            "the entries file contains unknown keys, which saving it would drop"
+        )),
     }
 }
 
+/// Tells whether a document is the serialization of the value parsed from it,
+/// `None` if it holds content the value doesn't
+pub fn canonical_form<T: Serialize>(document: &str, value: &T) -> Result<Option<bool>> {
+    if toml::from_str::<toml::Value>(document)? != toml::Value::try_from(value)? {
+        return Ok(None);
+    }
+    Ok(Some(toml::to_string(value)? == document))
+}
+
+/// Saves the entries of the current store
 pub fn save_entries(passphrase: SecretString, storage: &Storage) -> Result<()> {
-    let bytes: Vec<u8> = toml::to_vec(&storage)?;
-    let encrypted = encrypt(&bytes, passphrase)?;
-    let mut file = File::create(entries_file()?)?;
+    layout::current()?.save(passphrase, storage)
+}
+
+/// Writes an entries file encrypted with the given settings
+pub fn write_entries_file_with(
+    path: &str,
+    passphrase: SecretString,
+    storage: &Storage,
+    settings: &StoreSettings,
+) -> Result<()> {
+    let encrypted = encrypt_document(storage, passphrase, settings)?;
+    let mut file = File::create(path)?;
     file.write_all(&encrypted)?;
     Ok(())
 }
@@ -0,0 +1,101 @@
+use crate::cli::EnvFormat;
+use anyhow::{anyhow, Result};
+
+/// Returns true if `name` can be used as an environment variable name
+pub fn is_valid_variable(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+            chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }
+        _ => false,
+    }
+}
+
+/// Derives a variable name from an entry name, e.g. `app/db-password` with the
+/// prefix `app/` becomes `DB_PASSWORD`
+pub fn mangle(prefix: &str, entry_name: &str) -> String {
+    let stripped = entry_name
+        .strip_prefix(prefix)
+        .unwrap_or(entry_name)
+        .trim_start_matches(|c: char| !c.is_ascii_alphanumeric());
+    let name = if stripped.is_empty() {
+        entry_name
+    } else {
+        stripped
+    };
+
+    let mut variable: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    if !variable.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+        variable.insert(0, '_');
+    }
+    variable
+}
+
+/// Formats a single variable assignment in the syntax of `format`
+pub fn assignment(format: &EnvFormat, variable: &str, value: &str) -> Result<String> {
+    if value.contains('\0') {
+        return Err(anyhow!(
+            "value of '{}' contains a NUL byte and can't be exported",
+            variable
+        ));
+    }
+
+    let line = match format {
+        EnvFormat::Dotenv => format!("{}={}", variable, quote_dotenv(value)),
+        EnvFormat::Sh => format!("export {}={}", variable, quote_sh(value)),
+        EnvFormat::Fish => format!("set -gx {} {}", variable, quote_fish(value)),
+        EnvFormat::Nushell => format!("$env.{} = {}", variable, quote_nushell(value)),
+    };
+    Ok(line)
+}
+
+/// Single quotes where possible, otherwise double quotes with backslash escapes
+fn quote_dotenv(value: &str) -> String {
+    if !value.contains(['\'', '\n', '\r']) {
+        return format!("'{}'", value);
+    }
+
+    let mut quoted = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '\\' => quoted.push_str("\\\\"),
+            '"' => quoted.push_str("\\\""),
+            '$' => quoted.push_str("\\$"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// POSIX single quotes, where a literal `'` has to be written as `'\''`
+pub fn quote_sh(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+/// fish single quotes only recognise `\\` and `\'` as escapes
+fn quote_fish(value: &str) -> String {
+    format!("'{}'", value.replace('\\', r"\\").replace('\'', r"\'"))
+}
+
+/// nushell raw strings, with enough `#`s to never be terminated early
+fn quote_nushell(value: &str) -> String {
+    let mut hashes = "#".to_string();
+    while value.contains(&format!("'{}", hashes)) {
+        hashes.push('#');
+    }
+    format!("r{}'{}'{}", hashes, value, hashes)
+}
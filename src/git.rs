@@ -0,0 +1,330 @@
+use crate::cli::Prefer;
+use crate::config;
+use crate::exports::quote_sh;
+use crate::hooks::HookEvent;
+use crate::layout;
+use crate::paths::{
+    storage_dir, BACKUPS_DIR_NAME, ENTRIES_DIR_NAME, ENTRIES_FILE_NAME, IDENTITY_FILE_NAME,
+    STORE_SETTINGS_FILE_NAME,
+};
+use anyhow::{anyhow, Result};
+use clap::ValueEnum;
+use std::fs;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+/// Runs git in a directory and returns its standard output
+pub fn git(dir: &str, args: &[&str]) -> Result<String> {
+    let output = Command::new("git")
+        .args(args)
+        .current_dir(dir)
+        .output()
+        .map_err(|e| anyhow!("failed to run git: {}", e))?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "`git {}` failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8(output.stdout)?)
+}
+
+/// Runs git in a directory with its output going to the terminal
+pub fn run(dir: &str, args: &[String]) -> Result<()> {
+    let status = Command::new("git")
+        .args(args)
+        .current_dir(dir)
+        .status()
+        .map_err(|e| anyhow!("failed to run git: {}", e))?;
+    if !status.success() {
+        return Err(anyhow!("`git {}` failed", args.join(" ")));
+    }
+    Ok(())
+}
+
+pub fn is_repository(dir: &str) -> bool {
+    git(dir, &["rev-parse", "--is-inside-work-tree"]).is_ok()
+}
+
+/// The upstream of the current branch, if it has one
+pub fn upstream(dir: &str) -> Option<String> {
+    git(
+        dir,
+        &["rev-parse", "--abbrev-ref", "--symbolic-full-name", "@{u}"],
+    )
+    .ok()
+    .map(|upstream| upstream.trim().to_string())
+}
+
+fn has_origin(dir: &str) -> bool {
+    git(dir, &["remote"])
+        .map(|remotes| remotes.lines().any(|remote| remote == "origin"))
+        .unwrap_or(false)
+}
+
+/// The files of the store which are committed. A key file never is, even if
+/// it is kept in the storage folder.
+fn store_files(dir: &str) -> Vec<&'static str> {
+    [
+        ENTRIES_FILE_NAME,
+        ENTRIES_DIR_NAME,
+        IDENTITY_FILE_NAME,
+        STORE_SETTINGS_FILE_NAME,
+        ".gitattributes",
+        ".gitignore",
+    ]
+    .iter()
+    .copied()
+    .filter(|file| Path::new(dir).join(file).exists())
+    .collect()
+}
+
+/// Commits the files of the store if they changed, leaving anything else
+/// staged alone. Returns whether there was anything to commit.
+fn commit(dir: &str, message: &str) -> Result<bool> {
+    let files = store_files(dir);
+    let status = [&["status", "--porcelain", "--"][..], &files].concat();
+    if git(dir, &status)?.trim().is_empty() {
+        return Ok(false);
+    }
+    git(dir, &[&["add", "--"][..], &files].concat())?;
+    git(
+        dir,
+        &[&["commit", "-q", "-m", message, "--"][..], &files].concat(),
+    )?;
+    Ok(true)
+}
+
+/// Pushes the current branch, to origin if it has no upstream yet
+fn push(dir: &str) -> Result<()> {
+    match upstream(dir) {
+        Some(_) => git(dir, &["push", "-q"])?,
+        None => git(dir, &["push", "-q", "-u", "origin", "HEAD"])?,
+    };
+    Ok(())
+}
+
+/// Pulls into the current branch from its upstream, or from `from` (e.g.
+/// `origin main`), aborting a merge which failed. The merge driver may ask
+/// for the passphrase, so git keeps the terminal, and what it would print on
+/// the standard output goes to the standard error.
+fn pull(dir: &str, from: &[&str]) -> Result<()> {
+    let args = [&["pull", "-q", "--no-rebase", "--no-edit"][..], from].concat();
+    let output = Command::new("git")
+        .args(&args)
+        .current_dir(dir)
+        .stdin(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|e| anyhow!("failed to run git: {}", e))?;
+    eprint!("{}", String::from_utf8_lossy(&output.stdout));
+    if !output.status.success() {
+        if git(dir, &["rev-parse", "-q", "--verify", "MERGE_HEAD"]).is_ok() {
+            let _ = git(dir, &["merge", "--abort"]);
+        }
+        return Err(anyhow!("`git {}` failed", args.join(" ")));
+    }
+    Ok(())
+}
+
+/// Pulls the branch of the same name from origin into a branch without an
+/// upstream, if origin has it, e.g. when setting up the store on another
+/// device. Pushing sets the upstream.
+fn pull_origin(dir: &str) -> Result<()> {
+    git(dir, &["fetch", "-q", "origin"])?;
+    let branch = git(dir, &["symbolic-ref", "--short", "HEAD"])?;
+    let branch = branch.trim();
+    let remote_branch = format!("refs/remotes/origin/{}", branch);
+    if git(dir, &["rev-parse", "-q", "--verify", &remote_branch]).is_ok() {
+        pull(dir, &["origin", branch])?;
+    }
+    Ok(())
+}
+
+/// Makes `dir` a git repository, unless it is in one already, sets up the
+/// merge and diff drivers, adds `remote` as origin and commits the store
+pub fn init(dir: &str, remote: Option<&str>) -> Result<()> {
+    if !is_repository(dir) {
+        git(dir, &["init", "-q"])?;
+    }
+    install_merge_driver(dir, None)?;
+    install_textconv(dir)?;
+    ignore_backups(dir)?;
+    if let Some(remote) = remote {
+        if !has_origin(dir) {
+            git(dir, &["remote", "add", "origin", remote])?;
+        } else if git(dir, &["remote", "get-url", "origin"])?.trim() != remote {
+            return Err(anyhow!(
+                "origin is already set to another remote, change it with `git remote set-url origin {}`",
+                remote
+            ));
+        }
+    }
+    commit(dir, "Add page store")?;
+    Ok(())
+}
+
+/// Commits local changes, pulls and pushes
+pub fn sync(dir: &str) -> Result<()> {
+    if !is_repository(dir) {
+        return Err(anyhow!(
+            "{} is not a git repository, run `page git init`",
+            dir
+        ));
+    }
+    commit(dir, "sync")?;
+    if upstream(dir).is_some() {
+        pull(dir, &[])?;
+    } else if has_origin(dir) {
+        pull_origin(dir)?;
+    } else {
+        return Err(anyhow!(
+            "no remote to sync with, add one with `git remote add origin <URL>`"
+        ));
+    }
+    push(dir)
+}
+
+/// Shows uncommitted changes and, after fetching if possible, how far the
+/// branch is ahead of or behind its upstream
+pub fn status(dir: &str) -> Result<()> {
+    if upstream(dir).is_some() {
+        if let Err(e) = git(dir, &["fetch", "-q"]) {
+            eprintln!("Warning: {}, the upstream may be out of date", e);
+        }
+    }
+    let status = ["status", "--short", "--branch"].map(String::from);
+    run(dir, &status)
+}
+
+/// Describes a change for its commit, e.g. `edit_entry: github`
+fn commit_message(event: &HookEvent, entries: &[String]) -> String {
+    match entries {
+        [] => event.name(),
+        _ => format!("{}: {}", event.name(), entries.join(", ")),
+    }
+}
+
+/// Commits and pushes a change to the store if `git_auto_commit` is set.
+/// Failing to do so only warns, as the change is saved already.
+pub fn commit_after_save(event: &HookEvent, entries: &[String]) -> Result<()> {
+    if config::load()?.git_auto_commit != Some(true) {
+        return Ok(());
+    }
+    let dir = storage_dir()?;
+    if !is_repository(&dir) {
+        eprintln!(
+            "Warning: {} is not a git repository, run `page git init`",
+            dir
+        );
+        return Ok(());
+    }
+    if let Err(e) = commit(&dir, &commit_message(event, entries)) {
+        eprintln!("Warning: {}", e);
+        return Ok(());
+    }
+    if upstream(&dir).is_some() || has_origin(&dir) {
+        if let Err(e) = push(&dir) {
+            eprintln!("Warning: {}, run `page git sync` later", e);
+        }
+    }
+    Ok(())
+}
+
+/// Pulls changes to the store if `git_auto_pull` is set and its branch has an
+/// upstream. When offline or if the changes can't be merged, the local store
+/// is used.
+pub fn pull_before_load() -> Result<()> {
+    if config::load()?.git_auto_pull != Some(true) {
+        return Ok(());
+    }
+    let dir = storage_dir()?;
+    if upstream(&dir).is_none() {
+        return Ok(());
+    }
+    if let Err(e) = pull(&dir, &[]) {
+        eprintln!("Warning: {}, using the local store", e);
+    }
+    Ok(())
+}
+
+/// Assigns an attribute to the files holding entries in the `.gitattributes`
+/// of a directory, unless it is already assigned
+pub fn add_attribute(dir: &str, attribute: &str) -> Result<()> {
+    let path = Path::new(dir).join(".gitattributes");
+    let line = format!("{} {}", layout::open(Path::new(dir))?.pattern(), attribute);
+    let mut attributes = fs::read_to_string(&path).unwrap_or_default();
+    if attributes.lines().any(|l| l.trim() == line) {
+        return Ok(());
+    }
+    if !attributes.is_empty() && !attributes.ends_with('\n') {
+        attributes.push('\n');
+    }
+    attributes.push_str(&line);
+    attributes.push('\n');
+    fs::write(path, attributes)?;
+    Ok(())
+}
+
+/// Keeps the backups of the entries file out of the repository in `dir`,
+/// git keeps earlier versions itself
+fn ignore_backups(dir: &str) -> Result<()> {
+    let path = Path::new(dir).join(".gitignore");
+    let line = format!("{}/", BACKUPS_DIR_NAME);
+    let mut ignored = fs::read_to_string(&path).unwrap_or_default();
+    if ignored.lines().any(|l| l.trim() == line) {
+        return Ok(());
+    }
+    if !ignored.is_empty() && !ignored.ends_with('\n') {
+        ignored.push('\n');
+    }
+    ignored.push_str(&line);
+    ignored.push('\n');
+    fs::write(path, ignored)?;
+    Ok(())
+}
+
+/// The shell command git runs for a driver: this executable with the given
+/// arguments, for the store in `dir` whatever git's working directory is
+fn driver_command(dir: &str, args: &str) -> Result<String> {
+    let exe = std::env::current_exe()?;
+    Ok(format!(
+        "PAGE_STORAGE_FOLDER={} {} {}",
+        quote_sh(dir),
+        quote_sh(&exe.display().to_string()),
+        args
+    ))
+}
+
+/// Sets up `page git-merge-driver` for the files holding entries in the git
+/// repository in `dir`
+pub fn install_merge_driver(dir: &str, prefer: Option<Prefer>) -> Result<()> {
+    let mut args = "git-merge-driver".to_string();
+    if let Some(value) = prefer.as_ref().and_then(ValueEnum::to_possible_value) {
+        args.push_str(&format!(" --prefer {}", value.get_name()));
+    }
+    args.push_str(" %O %A %B %P");
+    git(dir, &["config", "merge.page.name", "page entry-wise merge"])?;
+    git(
+        dir,
+        &["config", "merge.page.driver", &driver_command(dir, &args)?],
+    )?;
+    add_attribute(dir, "merge=page")
+}
+
+/// Sets up `page git-diff-textconv` for the files holding entries in the git
+/// repository in `dir`
+pub fn install_textconv(dir: &str) -> Result<()> {
+    git(
+        dir,
+        &[
+            "config",
+            "diff.page.textconv",
+            &driver_command(dir, "git-diff-textconv")?,
+        ],
+    )?;
+    // the listing of a blob doesn't change, git keeps it in refs/notes/textconv/page
+    git(dir, &["config", "diff.page.cachetextconv", "true"])?;
+    add_attribute(dir, "diff=page")
+}
@@ -0,0 +1,161 @@
+use crate::entries::Entry;
+use anyhow::{anyhow, Result};
+use std::io::BufRead;
+
+/// Attributes git passes to a credential helper, see `git help credential`
+#[derive(Debug, Default)]
+pub struct Request {
+    pub protocol: Option<String>,
+    pub host: Option<String>,
+    pub path: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+impl Request {
+    /// Reads `key=value` lines up to a blank line or the end of the input
+    pub fn parse<R: BufRead>(mut input: R) -> Result<Self> {
+        let mut request = Request::default();
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if input.read_line(&mut line)? == 0 {
+                break;
+            }
+            let line = line.trim_end_matches(['\n', '\r']);
+            if line.is_empty() {
+                break;
+            }
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| anyhow!("invalid credential attribute '{}'", line))?;
+            let value = Some(value.to_string());
+            match key {
+                "protocol" => request.protocol = value,
+                "host" => request.host = value,
+                "path" => request.path = value,
+                "username" => request.username = value,
+                "password" => request.password = value,
+                // attributes we don't use, e.g. `capability[]` or `wwwauth[]`
+                _ => {}
+            }
+        }
+        Ok(request)
+    }
+
+    fn host(&self) -> Result<&str> {
+        self.host
+            .as_deref()
+            .ok_or_else(|| anyhow!("credential request without a host"))
+    }
+
+    /// The url stored in entries created by `store`
+    pub fn url(&self) -> Result<String> {
+        let mut url = match &self.protocol {
+            Some(protocol) => format!("{}://{}", protocol, self.host()?),
+            None => self.host()?.to_string(),
+        };
+        if let Some(path) = &self.path {
+            url.push('/');
+            url.push_str(path.trim_matches('/'));
+        }
+        Ok(url)
+    }
+
+    /// The name of entries created by `store`, e.g. `git/github.com/user`
+    pub fn entry_name(&self) -> Result<String> {
+        let mut name = format!("git/{}", self.host()?);
+        if let Some(path) = &self.path {
+            name.push('/');
+            name.push_str(path.trim_matches('/'));
+        }
+        if let Some(username) = &self.username {
+            name.push('/');
+            name.push_str(username);
+        }
+        Ok(name)
+    }
+
+    /// Returns how specific the match between the request and an entry is,
+    /// or `None` if the entry's url and username don't fit the request
+    pub fn matches(&self, entry: &Entry) -> Option<usize> {
+        let host = self.host.as_deref()?;
+        let url = EntryUrl::parse(entry.url.as_deref()?);
+
+        if !url.host.eq_ignore_ascii_case(host) {
+            return None;
+        }
+        if let (Some(expected), Some(protocol)) = (url.protocol, &self.protocol) {
+            if !expected.eq_ignore_ascii_case(protocol) {
+                return None;
+            }
+        }
+        if let (Some(expected), Some(username)) = (&entry.username, &self.username) {
+            if expected != username {
+                return None;
+            }
+        }
+
+        // git only sends the path if `credential.useHttpPath` is set
+        let mut score = 1;
+        if let (Some(expected), Some(path)) = (url.path, &self.path) {
+            let path = path.trim_matches('/');
+            let is_parent = path
+                .strip_prefix(expected)
+                .map(|rest| rest.is_empty() || rest.starts_with('/'))
+                .unwrap_or(false);
+            if !is_parent {
+                return None;
+            }
+            score += expected.len();
+        }
+        Some(score)
+    }
+
+    /// Whether an entry holds exactly the credential described by the request
+    pub fn is_stored_in(&self, entry: &Entry) -> bool {
+        self.url().ok() == entry.url && self.username == entry.username
+    }
+}
+
+/// The parts of an entry's url relevant for matching credentials
+struct EntryUrl<'a> {
+    protocol: Option<&'a str>,
+    host: &'a str,
+    path: Option<&'a str>,
+}
+
+impl<'a> EntryUrl<'a> {
+    fn parse(url: &'a str) -> Self {
+        let (protocol, rest) = match url.split_once("://") {
+            Some((protocol, rest)) => (Some(protocol), rest),
+            None => (None, url),
+        };
+        let (host, path) = match rest.split_once('/') {
+            Some((host, path)) => (host, Some(path.trim_matches('/'))),
+            None => (rest, None),
+        };
+        // strip credentials embedded in the url, e.g. `https://user@host`
+        let host = host.rsplit('@').next().unwrap_or(host);
+        EntryUrl {
+            protocol,
+            host,
+            path: path.filter(|p| !p.is_empty()),
+        }
+    }
+}
+
+/// Formats the response to a `get` request
+pub fn response(entry: &Entry) -> Result<String> {
+    let mut response = String::new();
+    if let Some(username) = &entry.username {
+        response.push_str(&format!("username={}\n", username));
+    }
+    if entry.password.contains(['\n', '\0']) {
+        return Err(anyhow!(
+            "password contains characters not allowed by the credential protocol"
+        ));
+    }
+    response.push_str(&format!("password={}\n", entry.password));
+    Ok(response)
+}
@@ -1,3 +1,4 @@
+use crate::config;
 use crate::paths::{hooks_dir, storage_dir};
 use anyhow::{anyhow, Result};
 use std::fs;
@@ -27,27 +28,42 @@ pub enum HookEvent {
     ShowEntry,
     EditEntry,
     RemoveEntry,
+    CopyEntry,
+    MoveEntry,
+    MergeEntries,
+    FormatEntries,
+    ExportEntries,
+    ChangePassphrase,
 }
 
 impl HookEvent {
-    fn name(&self) -> String {
+    pub fn name(&self) -> String {
         match *self {
             Self::NewEntry => "new_entry".to_string(),
             Self::ListEntries => "list_entries".to_string(),
             Self::ShowEntry => "show_entry".to_string(),
             Self::EditEntry => "edit_entry".to_string(),
             Self::RemoveEntry => "remove_entry".to_string(),
+            Self::CopyEntry => "copy_entry".to_string(),
+            Self::MoveEntry => "move_entry".to_string(),
+            Self::MergeEntries => "merge_entries".to_string(),
+            Self::FormatEntries => "format_entries".to_string(),
+            Self::ExportEntries => "export_entries".to_string(),
+            Self::ChangePassphrase => "change_passphrase".to_string(),
         }
     }
 }
 
 pub fn run_hook(hook: &Hook, event: &HookEvent) -> Result<()> {
+    if config::load()?.hooks == Some(false) {
+        return Ok(());
+    }
     let path = Path::new(&hooks_dir()?)
         .join(hook.name())
         .display()
         .to_string();
     if fs::metadata(&path).is_ok() {
-        println!("Running {} hook", hook.name());
+        eprintln!("Running {} hook", hook.name());
         let storage_dir = storage_dir()?;
         let output = Command::new(path)
             .args(&[event.name()])
@@ -57,11 +73,12 @@ pub fn run_hook(hook: &Hook, event: &HookEvent) -> Result<()> {
         let stdout = String::from_utf8(output.stdout)?;
         let stderr = String::from_utf8(output.stderr)?;
 
+        // hook output goes to stderr to keep stdout usable by other programs
         for line in stdout.lines() {
-            println!("{}: {}", hook.name(), line);
+            eprintln!("{}: {}", hook.name(), line);
         }
         for line in stderr.lines() {
-            println!("{}: {}", hook.name(), line);
+            eprintln!("{}: {}", hook.name(), line);
         }
 
         if !output.status.success() {
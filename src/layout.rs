@@ -0,0 +1,619 @@
+use crate::backend::{self, conflict, Backend};
+use crate::cli::Layout;
+use crate::entries::{
+    self, canonical_form, decrypt_document, decrypt_entries, encrypt_document, encrypt_entries,
+    parse_document, read_entries_file_with, write_entries_file_with, Entry, Storage,
+};
+use crate::paths::{
+    storage_dir, ENTRIES_DIR_NAME, ENTRIES_FILE_NAME, IDENTITY_FILE_NAME, STORE_SETTINGS_FILE_NAME,
+};
+use crate::settings::StoreSettings;
+use crate::utilities::{
+    armor, dearmor, decrypt_with_key, encrypt_to_key, scrypt_work_factor, to_hex,
+};
+use age::secrecy::{ExposeSecret, SecretString};
+use age::x25519;
+use anyhow::{anyhow, Context, Error, Result};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Keeps the entries of a store in its storage folder or a backend, in the
+/// layout chosen at `page init`
+pub trait EntryStore {
+    /// What holds the entries: a `file` or a `folder`
+    fn kind(&self) -> &'static str;
+
+    /// The path of the file or folder holding the entries, or the URL of
+    /// the entries file if it is kept remotely
+    fn path(&self) -> String;
+
+    /// Whether the entries are kept on another machine, see `Backend::remote`
+    fn remote(&self) -> bool;
+
+    /// The scrypt work factor the passphrase is used with, if the store exists
+    fn scrypt_work_factor(&self) -> Result<Option<u8>>;
+
+    /// The files holding entries, as a pattern of `.gitattributes`
+    fn pattern(&self) -> &'static str;
+
+    /// Whether the store was initialized
+    fn exists(&self) -> Result<bool>;
+
+    /// Initializes an empty store
+    fn create(&self, passphrase: SecretString) -> Result<()>;
+
+    fn load(&self, passphrase: SecretString) -> Result<Storage>;
+
+    /// Saves the entries, unless the store was changed since they were loaded
+    fn save(&self, passphrase: SecretString, storage: &Storage) -> Result<()>;
+
+    /// Encrypts the store with a new passphrase
+    fn change_passphrase(
+        &self,
+        passphrase: SecretString,
+        new: SecretString,
+        storage: &Storage,
+    ) -> Result<()>;
+
+    /// Decrypts the store and tells whether it holds the canonical documents
+    /// saving writes
+    fn check_canonical(&self, passphrase: SecretString) -> Result<(Storage, bool)>;
+
+    /// Reads a revision of a file holding entries, as git passes them to its
+    /// drivers. `path` is the path of the file in the store, if known.
+    fn read_revision(
+        &self,
+        file: &Path,
+        path: Option<&Path>,
+        passphrase: SecretString,
+    ) -> Result<Storage>;
+
+    /// Writes a revision of a file holding entries, as read by `read_revision`
+    fn write_revision(
+        &self,
+        file: &Path,
+        passphrase: SecretString,
+        storage: &Storage,
+    ) -> Result<()>;
+
+    /// The version of the encrypted store, which changes with it. Saving
+    /// checks it, and the agent notices changes made by other programs with
+    /// it, e.g. by `git pull`.
+    fn version(&self) -> Result<String>;
+
+    /// Where backups of earlier versions of the store are, newest first
+    fn backups(&self) -> Result<Vec<String>>;
+}
+
+/// Opens the store in a storage folder
+pub fn open(dir: &Path) -> Result<Box<dyn EntryStore>> {
+    let settings = StoreSettings::load_from(&dir.join(STORE_SETTINGS_FILE_NAME))?;
+    Ok(match settings.layout {
+        Layout::SingleFile => Box::new(EntriesFile {
+            backend: backend::open(dir, &settings)?,
+            settings,
+        }),
+        Layout::FilePerEntry if settings.s3.is_some() => {
+            return Err(anyhow!(
+                "a store with a file per entry can't be kept in an S3 bucket"
+            ))
+        }
+        Layout::FilePerEntry => Box::new(EntryFiles {
+            dir: dir.join(ENTRIES_DIR_NAME),
+            identity_file: dir.join(IDENTITY_FILE_NAME),
+            settings,
+        }),
+    })
+}
+
+/// Opens the current store
+pub fn current() -> Result<Box<dyn EntryStore>> {
+    open(Path::new(&storage_dir()?))
+}
+
+/// All entries in a single entries file, encrypted with the passphrase and
+/// kept by a backend
+struct EntriesFile {
+    backend: Box<dyn Backend>,
+    settings: StoreSettings,
+}
+
+impl EntriesFile {
+    /// Decrypts the entries file into the document it holds and its version
+    fn read_document(&self, passphrase: SecretString) -> Result<(String, String)> {
+        let (encrypted, version) = self
+            .backend
+            .read()?
+            .ok_or_else(|| anyhow!("storage not initialized, run `page init`"))?;
+        let document =
+            decrypt_document(&encrypted, passphrase, &self.settings).with_context(|| {
+                format!("Failed to decrypt entries file {}", self.backend.location())
+            })?;
+        Ok((document, version))
+    }
+}
+
+impl EntryStore for EntriesFile {
+    fn kind(&self) -> &'static str {
+        "file"
+    }
+
+    fn path(&self) -> String {
+        self.backend.location()
+    }
+
+    fn remote(&self) -> bool {
+        self.backend.remote()
+    }
+
+    fn scrypt_work_factor(&self) -> Result<Option<u8>> {
+        match self.backend.read()? {
+            Some((encrypted, _)) => Ok(scrypt_work_factor(&dearmor(&encrypted)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn pattern(&self) -> &'static str {
+        ENTRIES_FILE_NAME
+    }
+
+    fn exists(&self) -> Result<bool> {
+        Ok(self.backend.version()?.is_some())
+    }
+
+    fn create(&self, passphrase: SecretString) -> Result<()> {
+        self.save(passphrase, &Storage::default())
+    }
+
+    fn load(&self, passphrase: SecretString) -> Result<Storage> {
+        let (document, version) = self.read_document(passphrase)?;
+        let mut storage = parse_document(&document)?;
+        storage.version = Some(version);
+        Ok(storage)
+    }
+
+    fn save(&self, passphrase: SecretString, storage: &Storage) -> Result<()> {
+        let encrypted = encrypt_document(storage, passphrase, &self.settings)?;
+        self.backend.write(&encrypted, storage.version.as_deref())?;
+        Ok(())
+    }
+
+    fn change_passphrase(
+        &self,
+        _passphrase: SecretString,
+        new: SecretString,
+        storage: &Storage,
+    ) -> Result<()> {
+        self.save(new, storage)?;
+        // they are encrypted with the old passphrase
+        self.backend.remove_backups()
+    }
+
+    fn check_canonical(&self, passphrase: SecretString) -> Result<(Storage, bool)> {
+        let (document, version) = self.read_document(passphrase)?;
+        let (mut storage, canonical) = entries::check_canonical(&document)?;
+        storage.version = Some(version);
+        Ok((storage, canonical))
+    }
+
+    fn read_revision(
+        &self,
+        file: &Path,
+        _path: Option<&Path>,
+        passphrase: SecretString,
+    ) -> Result<Storage> {
+        read_entries_file_with(&file.display().to_string(), passphrase, &self.settings)
+    }
+
+    fn write_revision(
+        &self,
+        file: &Path,
+        passphrase: SecretString,
+        storage: &Storage,
+    ) -> Result<()> {
+        write_entries_file_with(
+            &file.display().to_string(),
+            passphrase,
+            storage,
+            &self.settings,
+        )
+    }
+
+    fn version(&self) -> Result<String> {
+        self.backend
+            .version()?
+            .ok_or_else(|| anyhow!("storage not initialized, run `page init`"))
+    }
+
+    fn backups(&self) -> Result<Vec<String>> {
+        self.backend.backups()
+    }
+}
+
+/// Each entry in its own file in the entries folder, named after the entry,
+/// like pass does. The entry files are encrypted to a key of the store, which
+/// is kept in the identity file encrypted with the passphrase, so that
+/// loading takes a single scrypt run however many entries there are.
+struct EntryFiles {
+    dir: PathBuf,
+    identity_file: PathBuf,
+    settings: StoreSettings,
+}
+
+impl EntryFiles {
+    fn identity(&self, passphrase: SecretString) -> Result<x25519::Identity> {
+        let path = self.identity_file.display();
+        let encrypted =
+            fs::read(&self.identity_file).with_context(|| format!("Failed to open {}", path))?;
+        let decrypted = decrypt_entries(&encrypted, passphrase, &self.settings)
+            .with_context(|| format!("Failed to decrypt identity file {}", path))?;
+        String::from_utf8(decrypted)?
+            .trim()
+            .parse()
+            .map_err(|_| anyhow!("{} holds no age identity", path))
+    }
+
+    /// Writes the identity file next to the previous one and then replaces it,
+    /// so that the store isn't lost if writing fails
+    fn write_identity(&self, identity: &x25519::Identity, passphrase: SecretString) -> Result<()> {
+        let new = staged(&self.identity_file);
+        fs::write(&new, self.encrypt_identity(identity, passphrase)?)?;
+        fs::rename(&new, &self.identity_file)?;
+        Ok(())
+    }
+
+    fn encrypt_identity(
+        &self,
+        identity: &x25519::Identity,
+        passphrase: SecretString,
+    ) -> Result<Vec<u8>> {
+        encrypt_entries(
+            identity.to_string().expose_secret().as_bytes(),
+            passphrase,
+            &self.settings,
+        )
+    }
+
+    /// The file of an entry. Names are paths in the entries folder, so they
+    /// can't have empty, `.` or `..` components, nor characters which some
+    /// file systems don't allow in file names.
+    fn entry_file(&self, name: &str) -> Result<PathBuf> {
+        let valid = name.split('/').all(|part| {
+            !part.is_empty()
+                && part != "."
+                && part != ".."
+                && !part.contains(['\\', ':', '*', '?', '"', '<', '>', '|'])
+        });
+        if !valid {
+            return Err(anyhow!(
+                "'{}' can't be used as the file name of an entry",
+                name
+            ));
+        }
+        Ok(self.dir.join(format!("{}.age", name)))
+    }
+
+    /// The name of the entry a file in the store holds
+    fn entry_name(&self, path: &Path) -> Option<String> {
+        let relative = path
+            .strip_prefix(&self.dir)
+            .or_else(|_| path.strip_prefix(ENTRIES_DIR_NAME))
+            .ok()?;
+        let parts: Option<Vec<&str>> = relative.iter().map(|part| part.to_str()).collect();
+        parts?.join("/").strip_suffix(".age").map(String::from)
+    }
+
+    /// The entry files by the name of their entry
+    fn entry_files(&self) -> Result<BTreeMap<String, PathBuf>> {
+        let mut files = BTreeMap::new();
+        // git doesn't keep empty folders
+        if !self.dir.is_dir() {
+            return Ok(files);
+        }
+        let mut dirs = vec![self.dir.clone()];
+        while let Some(dir) = dirs.pop() {
+            let items =
+                fs::read_dir(&dir).with_context(|| format!("Failed to read {}", dir.display()))?;
+            for item in items {
+                let path = item?.path();
+                if path.is_dir() {
+                    dirs.push(path);
+                } else if let Some(name) = self.entry_name(&path) {
+                    files.insert(name, path);
+                }
+            }
+        }
+        Ok(files)
+    }
+
+    fn decrypt_entry(&self, encrypted: &[u8], identity: &x25519::Identity) -> Result<String> {
+        let decrypted = decrypt_with_key(&dearmor(encrypted)?, identity)?;
+        Ok(String::from_utf8(decrypted)?)
+    }
+
+    fn encrypt_entry(&self, document: &str, identity: &x25519::Identity) -> Result<Vec<u8>> {
+        let encrypted = encrypt_to_key(document.as_bytes(), &identity.to_public())?;
+        if self.settings.armor {
+            armor(&encrypted)
+        } else {
+            Ok(encrypted)
+        }
+    }
+
+    /// Decrypts the entry files into the documents they hold, or why they
+    /// can't be
+    fn read_documents(
+        &self,
+        identity: &x25519::Identity,
+    ) -> Result<BTreeMap<String, (PathBuf, Result<String>)>> {
+        let mut documents = BTreeMap::new();
+        for (name, path) in self.entry_files()? {
+            let document = fs::read(&path)
+                .map_err(Error::from)
+                .and_then(|encrypted| self.decrypt_entry(&encrypted, identity));
+            documents.insert(name, (path, document));
+        }
+        Ok(documents)
+    }
+
+    /// Removes an entry file and the folders this leaves empty
+    fn remove_entry_file(&self, path: &Path) -> Result<()> {
+        fs::remove_file(path)?;
+        let mut dir = path.parent();
+        while let Some(parent) = dir {
+            if parent == self.dir || fs::remove_dir(parent).is_err() {
+                break;
+            }
+            dir = parent.parent();
+        }
+        Ok(())
+    }
+}
+
+/// Fails if two entries would have the same file, or files in the same folder,
+/// on a case-insensitive file system, where saving one would overwrite the other
+fn check_case_collisions<'a>(names: impl Iterator<Item = &'a String>) -> Result<()> {
+    let mut seen = BTreeMap::new();
+    for name in names {
+        let parts: Vec<&str> = name.split('/').collect();
+        for end in 1..=parts.len() {
+            // a folder and an entry file of the same name don't collide
+            let mut path = parts[..end].join("/");
+            if end == parts.len() {
+                path.push_str(".age");
+            }
+            match seen.get(&path.to_lowercase()) {
+                Some((other_path, other)) if *other_path != path => {
+                    return Err(anyhow!(
+                        "the entries '{}' and '{}' can't both be kept, a case-insensitive file system doesn't tell their files apart",
+                        other,
+                        name
+                    ))
+                }
+                Some(_) => {}
+                None => {
+                    seen.insert(path.to_lowercase(), (path, name));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Where a file is written before it replaces `path`
+fn staged(path: &Path) -> PathBuf {
+    let mut staged = path.as_os_str().to_owned();
+    staged.push(".new");
+    PathBuf::from(staged)
+}
+
+impl EntryStore for EntryFiles {
+    fn kind(&self) -> &'static str {
+        "folder"
+    }
+
+    fn path(&self) -> String {
+        self.dir.display().to_string()
+    }
+
+    fn remote(&self) -> bool {
+        false
+    }
+
+    fn scrypt_work_factor(&self) -> Result<Option<u8>> {
+        match fs::read(&self.identity_file) {
+            Ok(encrypted) => Ok(scrypt_work_factor(&dearmor(&encrypted)?)),
+            Err(_) => Ok(None),
+        }
+    }
+
+    fn pattern(&self) -> &'static str {
+        "entries/**/*.age"
+    }
+
+    fn exists(&self) -> Result<bool> {
+        Ok(self.identity_file.exists())
+    }
+
+    fn create(&self, passphrase: SecretString) -> Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        self.write_identity(&x25519::Identity::generate(), passphrase)
+    }
+
+    /// Entry files which can't be read are skipped with a warning, so that a
+    /// single damaged file doesn't keep the others from being used
+    fn load(&self, passphrase: SecretString) -> Result<Storage> {
+        let identity = self.identity(passphrase)?;
+        let mut storage = Storage {
+            version: Some(self.version()?),
+            ..Storage::default()
+        };
+        for (name, (_, document)) in self.read_documents(&identity)? {
+            match document.and_then(|document| Ok(toml::from_str::<Entry>(&document)?)) {
+                Ok(entry) => {
+                    storage.entries.insert(name, entry);
+                }
+                Err(e) => eprintln!("Warning: skipping entry '{}': {:#}", name, e),
+            }
+        }
+        Ok(storage)
+    }
+
+    /// Writes only the entry files whose document changed, so that a commit
+    /// touches the changed entries only
+    fn save(&self, passphrase: SecretString, storage: &Storage) -> Result<()> {
+        if let Some(version) = &storage.version {
+            if *version != self.version()? {
+                return Err(conflict(&self.path()));
+            }
+        }
+        let identity = self.identity(passphrase)?;
+        let mut stored = self.read_documents(&identity)?;
+        check_case_collisions(storage.entries.keys().chain(stored.keys()))?;
+
+        let mut changed = vec![];
+        for (name, entry) in &storage.entries {
+            let path = self.entry_file(name)?;
+            let document = toml::to_string(entry)?;
+            match stored.remove(name) {
+                Some((_, Ok(stored))) if stored == document => {}
+                _ => changed.push((path, document)),
+            }
+        }
+        for (path, document) in changed {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::write(&path, self.encrypt_entry(&document, &identity)?)?;
+        }
+
+        // files which can't be read were never loaded, so their entries
+        // weren't removed
+        for (path, document) in stored.values() {
+            if document.is_ok() {
+                self.remove_entry_file(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The entry files are encrypted to a new key, as the identity file in the
+    /// git history still gives the old one to the old passphrase. The new files
+    /// are all written next to the current ones before any of them replaces
+    /// one, the identity file last.
+    fn change_passphrase(
+        &self,
+        passphrase: SecretString,
+        new: SecretString,
+        _storage: &Storage,
+    ) -> Result<()> {
+        let identity = self.identity(passphrase)?;
+        let rekeyed = x25519::Identity::generate();
+        let mut files = vec![];
+        for (name, (path, document)) in self.read_documents(&identity)? {
+            let document = document.with_context(|| format!("Failed to read entry '{}'", name))?;
+            files.push((path, self.encrypt_entry(&document, &rekeyed)?));
+        }
+        files.push((
+            self.identity_file.clone(),
+            self.encrypt_identity(&rekeyed, new)?,
+        ));
+
+        let written = files
+            .iter()
+            .try_for_each(|(path, encrypted)| fs::write(staged(path), encrypted));
+        if let Err(e) = written {
+            for (path, _) in &files {
+                let _ = fs::remove_file(staged(path));
+            }
+            return Err(e.into());
+        }
+        for (path, _) in &files {
+            fs::rename(staged(path), path)?;
+        }
+        Ok(())
+    }
+
+    fn check_canonical(&self, passphrase: SecretString) -> Result<(Storage, bool)> {
+        let identity = self.identity(passphrase)?;
+        let mut storage = Storage {
+            version: Some(self.version()?),
+            ..Storage::default()
+        };
+        let mut canonical = true;
+        for (name, (_, document)) in self.read_documents(&identity)? {
+            let document = document.with_context(|| format!("Failed to read entry '{}'", name))?;
+            let entry: Entry = toml::from_str(&document)?;
+            match canonical_form(&document, &entry)? {
+                Some(entry_canonical) => canonical &= entry_canonical,
+                None => {
+                    return Err(anyhow!(
+                        "entry '{}' contains unknown keys, which saving it would drop",
+                        name
+                    ))
+                }
+            }
+            storage.entries.insert(name, entry);
+        }
+        Ok((storage, canonical))
+    }
+
+    /// The revision holds the entry named after `path`, or an entry with an
+    /// empty name if the path isn't known. An empty file, as git passes for
+    /// an entry missing in a revision, holds none.
+    fn read_revision(
+        &self,
+        file: &Path,
+        path: Option<&Path>,
+        passphrase: SecretString,
+    ) -> Result<Storage> {
+        let identity = self.identity(passphrase)?;
+        let encrypted =
+            fs::read(file).with_context(|| format!("Failed to open {}", file.display()))?;
+        let mut storage = Storage::default();
+        if !encrypted.is_empty() {
+            let document = self.decrypt_entry(&encrypted, &identity)?;
+            let name = path
+                .and_then(|path| self.entry_name(path))
+                .unwrap_or_default();
+            storage.entries.insert(name, toml::from_str(&document)?);
+        }
+        Ok(storage)
+    }
+
+    fn write_revision(
+        &self,
+        file: &Path,
+        passphrase: SecretString,
+        storage: &Storage,
+    ) -> Result<()> {
+        let identity = self.identity(passphrase)?;
+        let entry = match storage.entries.values().collect::<Vec<_>>()[..] {
+            [entry] => entry,
+            _ => return Err(anyhow!("an entry file holds exactly one entry")),
+        };
+        let document = toml::to_string(entry)?;
+        fs::write(file, self.encrypt_entry(&document, &identity)?)?;
+        Ok(())
+    }
+
+    fn version(&self) -> Result<String> {
+        let mut hasher = Sha256::new();
+        let identity = fs::read(&self.identity_file)
+            .with_context(|| format!("Failed to read {}", self.identity_file.display()))?;
+        hasher.update(Sha256::digest(&identity));
+        for (name, path) in self.entry_files()? {
+            hasher.update(name.as_bytes());
+            hasher.update([0]);
+            hasher.update(Sha256::digest(&fs::read(path)?));
+        }
+        Ok(to_hex(&hasher.finalize()))
+    }
+
+    /// Stores with a file per entry keep no backups, git is better at that
+    fn backups(&self) -> Result<Vec<String>> {
+        Ok(vec![])
+    }
+}
@@ -1,48 +1,187 @@
+#[cfg(unix)]
+mod agent;
+mod askpass;
+mod aws;
+mod backend;
 mod cli;
+mod config;
+mod docker_credential;
 mod entries;
+mod exports;
+mod git;
+mod git_credential;
 mod hooks;
+mod layout;
+mod merge;
 mod paths;
+mod pinentry;
+mod s3;
+mod session;
+mod settings;
+mod textconv;
 mod utilities;
 use anyhow::Result;
 pub use clap::Parser;
 mod commands;
-use cli::{Cli, Cmd, KeyringCmd};
+use cli::{Cli, Cmd, ConfigCmd, GitCmd, KeyringCmd, StoreCmd};
+use std::ffi::OsString;
+use std::path::Path;
+
+/// Returns the command line arguments, treating an invocation via a link
+/// named e.g. `docker-credential-page <op>` (as done by docker) like
+/// `page docker-credential <op>`
+fn args() -> Vec<OsString> {
+    let mut args: Vec<OsString> = std::env::args_os().collect();
+    let invoked_as = args
+        .first()
+        .and_then(|arg| Path::new(arg).file_stem())
+        .and_then(|stem| stem.to_str());
+    let subcommand = match invoked_as {
+        Some("docker-credential-page") => Some("docker-credential"),
+        Some("page-askpass") => Some("askpass"),
+        _ => None,
+    };
+    if let Some(subcommand) = subcommand {
+        args.insert(1, OsString::from(subcommand));
+    }
+    args
+}
 
 fn main() -> Result<()> {
-    let opt = Cli::parse();
+    let mut opt = Cli::parse_from(args());
+    paths::select_store(opt.store.clone());
+    opt.unlock.apply_config(&config::load()?);
 
     match opt.cmd {
-        Cmd::Init => commands::init(opt.no_keyring),
+        Cmd::Init { options } => commands::init(options, &opt.unlock),
+        Cmd::Passwd { work_factor } => commands::passwd(work_factor, &opt.unlock),
         Cmd::New {
             entry_name,
-            username,
-            url,
-        } => commands::new_entry(entry_name, username, url, opt.no_keyring),
-        Cmd::List => commands::list(opt.no_keyring),
+            options,
+        } => commands::new_entry(entry_name, options, &opt.unlock),
+        Cmd::List => commands::list(&opt.unlock),
         Cmd::Show {
             entry_name,
             attribute,
+            field,
             on_screen,
-        } => commands::show(&entry_name, attribute, on_screen, opt.no_keyring),
+            clipboard_timeout,
+        } => commands::show(
+            &entry_name,
+            attribute,
+            field,
+            on_screen,
+            clipboard_timeout,
+            &opt.unlock,
+        ),
         Cmd::Edit {
             entry_name,
             new_name,
-            username,
-            url,
+            options,
+            remove_field,
             no_prompt,
         } => commands::edit(
             entry_name,
             new_name,
-            username,
-            url,
+            options,
+            remove_field,
             no_prompt,
-            opt.no_keyring,
+            &opt.unlock,
+        ),
+        Cmd::Remove { entry } => commands::remove(&entry, &opt.unlock),
+        Cmd::Cp {
+            source,
+            destination,
+            to,
+        } => commands::copy(
+            &source,
+            &destination,
+            false,
+            &opt.unlock,
+            &opt.unlock.for_destination(&to),
         ),
-        Cmd::Remove { entry } => commands::remove(&entry, opt.no_keyring),
+        Cmd::Mv {
+            source,
+            destination,
+            to,
+        } => commands::copy(
+            &source,
+            &destination,
+            true,
+            &opt.unlock,
+            &opt.unlock.for_destination(&to),
+        ),
+        Cmd::GitMergeDriver {
+            install: true,
+            prefer,
+            ..
+        } => commands::install_git_merge_driver(prefer),
+        Cmd::GitMergeDriver {
+            base: Some(base),
+            ours: Some(ours),
+            theirs: Some(theirs),
+            path,
+            prefer,
+            ..
+        } => {
+            commands::git_merge_driver(&base, &ours, &theirs, path.as_deref(), prefer, &opt.unlock)
+        }
+        Cmd::GitMergeDriver { .. } => unreachable!("the revisions are required without --install"),
+        Cmd::GitDiffTextconv { install: true, .. } => commands::install_git_diff_textconv(),
+        Cmd::GitDiffTextconv {
+            file: Some(file), ..
+        } => commands::git_diff_textconv(&file, &opt.unlock),
+        Cmd::GitDiffTextconv { .. } => unreachable!("the revision is required without --install"),
+        Cmd::Fmt { check } => commands::fmt(check, &opt.unlock),
+        Cmd::Backups => commands::backups(),
+        Cmd::Merge {
+            other,
+            base,
+            prefer,
+        } => commands::merge(&other, base.as_deref(), prefer, &opt.unlock),
+        Cmd::Env { prefix, format } => commands::env(&prefix, format, &opt.unlock),
+        Cmd::GitCredential { operation } => commands::git_credential(&operation, &opt.unlock),
+        Cmd::DockerCredential { operation, folder } => {
+            commands::docker_credential(operation, &folder, &opt.unlock)
+        }
+        Cmd::AwsCredentials { entry_name, fields } => {
+            commands::aws_credentials(&entry_name, &fields, &opt.unlock)
+        }
+        Cmd::Askpass { prompt } => commands::askpass(&prompt, &opt.unlock),
         Cmd::Info => commands::info(),
+        Cmd::Agent {
+            idle_timeout,
+            timeout,
+            foreground,
+        } => commands::agent(idle_timeout, timeout, foreground),
+        Cmd::Unlock { expiry, format } => commands::unlock(expiry, format, &opt.unlock),
+        Cmd::Lock => commands::lock(),
         Cmd::Keyring { cmd } => match cmd {
-            KeyringCmd::Check => commands::keyring_check(),
-            KeyringCmd::Forget => commands::keyring_forget(),
+            KeyringCmd::Check { all } => commands::keyring_check(all),
+            KeyringCmd::Set => commands::keyring_set(&opt.unlock),
+            KeyringCmd::Forget { all } => commands::keyring_forget(all),
+        },
+        Cmd::Git { cmd } => match cmd {
+            GitCmd::Init { remote } => commands::git_init(remote.as_deref()),
+            GitCmd::Sync => commands::git_sync(),
+            GitCmd::Log { args } => commands::git_log(args),
+            GitCmd::Status => commands::git_status(),
+        },
+        Cmd::Store { cmd } => match cmd {
+            StoreCmd::Add {
+                name,
+                path,
+                default,
+            } => commands::store_add(&name, &path, default),
+            StoreCmd::Remove { name } => commands::store_remove(&name),
+            StoreCmd::List => commands::store_list(),
+            StoreCmd::Default { name } => commands::store_default(&name),
+        },
+        Cmd::Config { cmd } => match cmd {
+            ConfigCmd::Get { key } => commands::config_get(&key),
+            ConfigCmd::Set { key, value, user } => commands::config_set(&key, Some(&value), user),
+            ConfigCmd::Unset { key, user } => commands::config_set(&key, None, user),
+            ConfigCmd::List => commands::config_list(),
         },
 
         Cmd::Completion { shell } => {
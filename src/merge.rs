@@ -0,0 +1,157 @@
+use crate::cli::Prefer;
+use crate::entries::Entry;
+use anyhow::Result;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// A side of a merge: our store or the entries file merged into it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Side {
+    Ours,
+    Theirs,
+}
+
+impl Side {
+    pub fn name(&self) -> &str {
+        match self {
+            Side::Ours => "ours",
+            Side::Theirs => "theirs",
+        }
+    }
+}
+
+/// An entry changed on both sides, `None` where it was removed
+pub struct Conflict<'a> {
+    pub name: &'a str,
+    pub ours: Option<&'a Entry>,
+    pub theirs: Option<&'a Entry>,
+}
+
+/// The changes a merge made to our entries
+#[derive(Default)]
+pub struct Summary {
+    pub added: Vec<String>,
+    pub updated: Vec<String>,
+    pub removed: Vec<String>,
+    /// Conflicting entries and the side which was kept
+    pub conflicts: Vec<(String, Side)>,
+}
+
+impl Summary {
+    /// Describes the changes, one per line
+    pub fn lines(&self) -> Vec<String> {
+        let mut lines = vec![];
+        for name in &self.added {
+            lines.push(format!("Added '{}'", name));
+        }
+        for name in &self.updated {
+            lines.push(format!("Updated '{}'", name));
+        }
+        for name in &self.removed {
+            lines.push(format!("Removed '{}'", name));
+        }
+        for (name, side) in &self.conflicts {
+            lines.push(format!("Conflict on '{}', kept {}", name, side.name()));
+        }
+        lines.push(format!(
+            "Merged {} change(s), resolved {} conflict(s)",
+            self.added.len() + self.updated.len() + self.removed.len(),
+            self.conflicts.len()
+        ));
+        lines
+    }
+
+    /// Whether our entries were changed
+    pub fn changed(&self) -> bool {
+        !(self.added.is_empty() && self.updated.is_empty() && self.removed.is_empty())
+            || self.conflicts.iter().any(|(_, side)| *side == Side::Theirs)
+    }
+}
+
+fn same(a: Option<&Entry>, b: Option<&Entry>) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => a.same_values(b),
+        (None, None) => true,
+        _ => false,
+    }
+}
+
+/// Resolves a conflict by policy. `Newer` keeps the entry which was changed
+/// last, and ours if that is unknown, e.g. because it was removed.
+pub fn prefer(policy: Prefer, conflict: &Conflict) -> Side {
+    match policy {
+        Prefer::Ours => Side::Ours,
+        Prefer::Theirs => Side::Theirs,
+        Prefer::Newer => {
+            let ours = conflict.ours.and_then(|e| e.modified);
+            let theirs = conflict.theirs.and_then(|e| e.modified);
+            match (ours, theirs) {
+                (Some(ours), Some(theirs)) if theirs > ours => Side::Theirs,
+                _ => Side::Ours,
+            }
+        }
+    }
+}
+
+/// Merges their entries into ours. With the entries both sides started from,
+/// an entry changed, added or removed on one side only takes that side.
+/// Without them, an entry missing on one side counts as added on the other,
+/// and an entry which differs is a conflict. Conflicts are decided by
+/// `resolve`, e.g. by the modification times with `Prefer::Newer`.
+pub fn merge(
+    base: Option<&BTreeMap<String, Entry>>,
+    ours: &mut BTreeMap<String, Entry>,
+    theirs: &BTreeMap<String, Entry>,
+    mut resolve: impl FnMut(&Conflict) -> Result<Side>,
+) -> Result<Summary> {
+    let names: BTreeSet<String> = ours.keys().chain(theirs.keys()).cloned().collect();
+
+    let mut summary = Summary::default();
+    for name in names {
+        let (our_entry, their_entry) = (ours.get(&name), theirs.get(&name));
+        if same(our_entry, their_entry) {
+            continue;
+        }
+
+        let side = match base.map(|base| base.get(&name)) {
+            Some(base) if same(base, our_entry) => Some(Side::Theirs),
+            Some(base) if same(base, their_entry) => Some(Side::Ours),
+            Some(_) => None,
+            None => match (our_entry, their_entry) {
+                (Some(_), None) => Some(Side::Ours),
+                (None, Some(_)) => Some(Side::Theirs),
+                _ => None,
+            },
+        };
+        let (side, conflict) = match side {
+            Some(side) => (side, false),
+            None => {
+                let side = resolve(&Conflict {
+                    name: &name,
+                    ours: our_entry,
+                    theirs: their_entry,
+                })?;
+                summary.conflicts.push((name.clone(), side));
+                (side, true)
+            }
+        };
+        if side == Side::Ours {
+            continue;
+        }
+
+        let changes = match theirs.get(&name) {
+            Some(entry) => match ours.insert(name.clone(), entry.clone()) {
+                Some(_) => &mut summary.updated,
+                None => &mut summary.added,
+            },
+            None => {
+                ours.remove(&name);
+                &mut summary.removed
+            }
+        };
+        // resolved conflicts are only listed as such
+        if !conflict {
+            changes.push(name);
+        }
+    }
+    Ok(summary)
+}
@@ -1,33 +1,148 @@
+use crate::config::{self, UserConfig};
 use anyhow::{anyhow, Result};
 use directories_next::ProjectDirs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 
-/// Returns the path to the storage folder containing the `entries_file`
+fn project_dirs() -> Result<ProjectDirs> {
+    ProjectDirs::from("", "", "page")
+        .ok_or_else(|| anyhow!("couldn't determine project storage folder"))
+}
+
+/// The named store selected with `--store`, if any
+static SELECTED_STORE: Mutex<Option<String>> = Mutex::new(None);
+
+/// Selects the named store which `storage_dir` resolves to, `None` for the
+/// store used by default
+pub fn select_store(name: Option<String>) {
+    *SELECTED_STORE.lock().unwrap() = name;
+}
+
+/// Returns the name of the store in use, if it is a named store
+pub fn store_name() -> Result<Option<String>> {
+    if let Some(name) = SELECTED_STORE.lock().unwrap().clone() {
+        return Ok(Some(name));
+    }
+    if std::env::var_os("PAGE_STORAGE_FOLDER").is_some() {
+        return Ok(None);
+    }
+    Ok(UserConfig::load()?.default_store)
+}
+
+/// Returns the storage folder of a named store
+pub fn named_storage_dir(name: &str) -> Result<String> {
+    match UserConfig::load()?.stores.get(name) {
+        Some(dir) => Ok(dir.display().to_string()),
+        None => Err(anyhow!("unknown store '{}', see `page store list`", name)),
+    }
+}
+
+/// Returns the path to the storage folder containing the `entries_file`: the
+/// folder of the store selected with `--store`, `PAGE_STORAGE_FOLDER`, the
+/// folder of the default store, or the user's data directory
 pub fn storage_dir() -> Result<String> {
+    if let Some(name) = store_name()? {
+        return named_storage_dir(&name);
+    }
     match std::env::var("PAGE_STORAGE_FOLDER") {
         Ok(f) => Ok(f),
-        Err(_) => match ProjectDirs::from("", "", "page") {
-            Some(pd) => {
-                let dir = pd.data_dir().display().to_string();
-                Ok(dir)
-            }
-            None => Err(anyhow!("couldn't determine project storage folder")),
-        },
+        Err(_) => Ok(project_dirs()?.data_dir().display().to_string()),
+    }
+}
+
+/// Returns the canonical path of the storage folder, which identifies the store
+pub fn canonical_storage_dir() -> Result<String> {
+    let dir = storage_dir()?;
+    match std::fs::canonicalize(&dir) {
+        Ok(path) => Ok(path.display().to_string()),
+        Err(_) => Ok(dir),
     }
 }
 
-/// Returns the path to the entries.toml.age file
-pub fn entries_file() -> Result<String> {
+/// The name of the entries file in the storage folder
+pub const ENTRIES_FILE_NAME: &str = "entries.toml.age";
+
+/// The name of the folder holding the backups of the entries file
+pub const BACKUPS_DIR_NAME: &str = "backups";
+
+/// The name of the folder holding a file per entry, for stores with that layout
+pub const ENTRIES_DIR_NAME: &str = "entries";
+
+/// The name of the file holding the key the entry files are encrypted to,
+/// encrypted with the passphrase
+pub const IDENTITY_FILE_NAME: &str = "identity.age";
+
+/// The name of the store settings file, kept next to the entries file
+pub const STORE_SETTINGS_FILE_NAME: &str = "store.toml";
+
+/// Returns the path to the settings of the store
+pub fn store_settings_file() -> Result<String> {
     Ok(Path::new(&storage_dir()?)
-        .join("entries.toml.age")
+        .join(STORE_SETTINGS_FILE_NAME)
+        .display()
+        .to_string())
+}
+
+/// Returns the path to the user config: `PAGE_CONFIG_FILE`, or `config.toml`
+/// in the user's config directory
+pub fn user_config_file() -> Result<String> {
+    if let Ok(file) = std::env::var("PAGE_CONFIG_FILE") {
+        return Ok(file);
+    }
+    Ok(project_dirs()?
+        .config_dir()
+        .join("config.toml")
         .display()
         .to_string())
 }
 
 /// Returns the path toth the hooks directory
 pub fn hooks_dir() -> Result<String> {
+    let dir = config::load()?
+        .hooks_dir
+        .unwrap_or_else(|| "hooks".to_string());
+    Ok(Path::new(&storage_dir()?).join(dir).display().to_string())
+}
+
+/// Returns the path to the file with the rules used by `page askpass`
+pub fn askpass_file() -> Result<String> {
     Ok(Path::new(&storage_dir()?)
-        .join("hooks")
+        .join("askpass.toml")
+        .display()
+        .to_string())
+}
+
+/// Returns the path to the list of stores with a passphrase in the keyring
+pub fn keyring_index_file() -> Result<String> {
+    Ok(project_dirs()?
+        .data_local_dir()
+        .join("keyring_stores")
         .display()
         .to_string())
 }
+
+/// Returns the directory for runtime files like sockets: `PAGE_RUNTIME_FOLDER`,
+/// or the user's runtime directory
+fn runtime_dir() -> Result<PathBuf> {
+    if let Ok(dir) = std::env::var("PAGE_RUNTIME_FOLDER") {
+        return Ok(PathBuf::from(dir));
+    }
+    match project_dirs()?.runtime_dir() {
+        Some(dir) => Ok(dir.to_path_buf()),
+        None => Ok(std::env::temp_dir().join(format!("page-{}", whoami::username()))),
+    }
+}
+
+/// Returns the path to the socket of `page agent`: `PAGE_AGENT_SOCKET`, or a
+/// socket in the user's runtime directory
+pub fn agent_socket() -> Result<String> {
+    if let Ok(socket) = std::env::var("PAGE_AGENT_SOCKET") {
+        return Ok(socket);
+    }
+    Ok(runtime_dir()?.join("agent.sock").display().to_string())
+}
+
+/// Returns the path to the directory holding the sessions created by `page unlock`
+pub fn sessions_dir() -> Result<String> {
+    Ok(runtime_dir()?.join("sessions").display().to_string())
+}
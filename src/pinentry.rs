@@ -0,0 +1,92 @@
+use age::secrecy::SecretString;
+use anyhow::{anyhow, Context, Result};
+use std::io::{BufRead, BufReader, Write};
+use std::process::{ChildStdout, Command, Stdio};
+
+/// Escapes a parameter of an Assuan command
+fn escape(value: &str) -> String {
+    value
+        .replace('%', "%25")
+        .replace('\n', "%0A")
+        .replace('\r', "%0D")
+}
+
+/// Decodes the `%XX` escapes of an Assuan data line
+fn unescape(data: &str) -> Result<String> {
+    let mut bytes = vec![];
+    let mut chars = data.bytes();
+    while let Some(b) = chars.next() {
+        if b == b'%' {
+            let hex: Vec<u8> = chars.by_ref().take(2).collect();
+            let hex = std::str::from_utf8(&hex)?;
+            bytes.push(
+                u8::from_str_radix(hex, 16)
+                    .map_err(|_| anyhow!("invalid escape in pinentry response"))?,
+            );
+        } else {
+            bytes.push(b);
+        }
+    }
+    Ok(String::from_utf8(bytes)?)
+}
+
+/// Reads response lines until `OK` or `ERR`, returning the data lines
+fn read_response(reader: &mut BufReader<ChildStdout>) -> Result<String> {
+    let mut data = String::new();
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(anyhow!("pinentry closed the connection unexpectedly"));
+        }
+        let line = line.trim_end_matches(['\n', '\r']);
+        if line == "OK" || line.starts_with("OK ") {
+            return Ok(data);
+        } else if let Some(error) = line.strip_prefix("ERR ") {
+            // e.g. `ERR 83886179 Operation cancelled`
+            let message = error.split_once(' ').map(|(_, m)| m).unwrap_or(error);
+            return Err(anyhow!("pinentry: {}", message));
+        } else if let Some(d) = line.strip_prefix("D ") {
+            data.push_str(&unescape(d)?);
+        }
+        // status (`S`) and comment (`#`) lines are ignored
+    }
+}
+
+/// Asks for a secret using a pinentry program, speaking the Assuan protocol
+pub fn get_pin(program: &str, description: &str, prompt: &str) -> Result<SecretString> {
+    let mut child = Command::new(program)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to run pinentry program '{}'", program))?;
+    let mut stdin = child
+        .stdin
+        .take()
+        .ok_or_else(|| anyhow!("no pinentry stdin"))?;
+    let mut reader = BufReader::new(
+        child
+            .stdout
+            .take()
+            .ok_or_else(|| anyhow!("no pinentry stdout"))?,
+    );
+
+    let pin = (|| {
+        read_response(&mut reader)?;
+        for command in [
+            "SETTITLE page".to_string(),
+            format!("SETDESC {}", escape(description)),
+            format!("SETPROMPT {}", escape(prompt.trim_end_matches([' ', ':']))),
+        ] {
+            writeln!(stdin, "{}", command)?;
+            read_response(&mut reader)?;
+        }
+        writeln!(stdin, "GETPIN")?;
+        read_response(&mut reader)
+    })();
+
+    let _ = writeln!(stdin, "BYE");
+    drop(stdin);
+    let _ = child.wait();
+
+    Ok(SecretString::from(pin?))
+}
@@ -0,0 +1,144 @@
+use crate::paths::sessions_dir;
+use crate::utilities::{decrypt_with_key, encrypt_to_key, now};
+use age::secrecy::{ExposeSecret, SecretString};
+use age::x25519;
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// The environment variable holding the token printed by `page unlock`
+pub const SESSION_VARIABLE: &str = "PAGE_SESSION";
+
+/// What is stored, encrypted to the session token, in a session file
+#[derive(Deserialize, Serialize)]
+struct Session {
+    /// The canonical storage folder of the unlocked store
+    store: String,
+    passphrase: String,
+    /// Seconds since the Unix epoch
+    expires: u64,
+}
+
+/// A session file is named after the public key of the token, so that the
+/// token alone locates it
+fn session_file(identity: &x25519::Identity) -> Result<PathBuf> {
+    Ok(Path::new(&sessions_dir()?).join(identity.to_public().to_string()))
+}
+
+/// Writes a file readable only by the user
+fn write_private(path: &Path, contents: &[u8]) -> Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)?.write_all(contents)?;
+    Ok(())
+}
+
+/// Removes session files whose expiry, stored unencrypted on their first
+/// line, has passed
+fn remove_expired(dir: &str) -> Result<()> {
+    for file in fs::read_dir(dir)? {
+        let path = file?.path();
+        let expires = fs::read(&path).ok().and_then(|contents| {
+            let line = contents.split(|b| *b == b'\n').next()?;
+            std::str::from_utf8(line).ok()?.parse::<u64>().ok()
+        });
+        if expires.map(|e| e <= now()).unwrap_or(true) {
+            let _ = fs::remove_file(path);
+        }
+    }
+    Ok(())
+}
+
+/// Caches the passphrase of a store on disk, encrypted to a new key, and
+/// returns the key as session token
+pub fn create(store: &str, passphrase: &SecretString, expiry: Duration) -> Result<SecretString> {
+    let dir = sessions_dir()?;
+    let mut builder = fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        builder.mode(0o700);
+    }
+    builder.create(&dir)?;
+    remove_expired(&dir)?;
+
+    let expires = now() + expiry.as_secs();
+    let session = serde_json::to_vec(&Session {
+        store: store.to_string(),
+        passphrase: passphrase.expose_secret().to_string(),
+        expires,
+    })?;
+
+    let identity = x25519::Identity::generate();
+    let mut contents = format!("{}\n", expires).into_bytes();
+    contents.extend(encrypt_to_key(&session, &identity.to_public())?);
+
+    write_private(&session_file(&identity)?, &contents)?;
+    Ok(identity.to_string())
+}
+
+/// Returns the passphrase of the session in `PAGE_SESSION`, if it is set
+/// and belongs to the given store
+pub fn passphrase(store: &str) -> Result<Option<SecretString>> {
+    let token = match std::env::var(SESSION_VARIABLE) {
+        Ok(token) if !token.is_empty() => token,
+        _ => return Ok(None),
+    };
+    let identity: x25519::Identity = token
+        .trim()
+        .parse()
+        .map_err(|_| anyhow!("{} is not a valid session token", SESSION_VARIABLE))?;
+    let path = session_file(&identity)?;
+    let contents = match fs::read(&path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(anyhow!(
+                "session expired or locked, run `page unlock` or unset {}",
+                SESSION_VARIABLE
+            ))
+        }
+        Err(e) => return Err(e).context("Failed to read session file"),
+    };
+    let encrypted = match contents.iter().position(|b| *b == b'\n') {
+        Some(end) => &contents[end + 1..],
+        None => return Err(anyhow!("invalid session file {}", path.display())),
+    };
+
+    let session: Session = serde_json::from_slice(&decrypt_with_key(encrypted, &identity)?)?;
+
+    if session.expires <= now() {
+        let _ = fs::remove_file(&path);
+        return Err(anyhow!(
+            "session expired, run `page unlock` or unset {}",
+            SESSION_VARIABLE
+        ));
+    }
+    if session.store != store {
+        return Ok(None);
+    }
+    Ok(Some(SecretString::from(session.passphrase)))
+}
+
+/// Invalidates all sessions, returns how many were active
+pub fn lock() -> Result<usize> {
+    let dir = sessions_dir()?;
+    if !Path::new(&dir).exists() {
+        return Ok(0);
+    }
+    remove_expired(&dir)?;
+    let mut count = 0;
+    for file in fs::read_dir(&dir)? {
+        fs::remove_file(file?.path())?;
+        count += 1;
+    }
+    Ok(count)
+}
@@ -0,0 +1,108 @@
+use crate::cli::Layout;
+use crate::config::Config;
+use crate::paths::{store_settings_file, ENTRIES_FILE_NAME};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Settings of a store, chosen at `page init` or with `page config set`, kept in `store.toml`
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct StoreSettings {
+    /// An age key file required in addition to the passphrase
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key_file: Option<PathBuf>,
+
+    /// The scrypt work factor (log2 of N) used to encrypt the entries, if not
+    /// picked by age for the device
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub work_factor: Option<u8>,
+
+    /// Write the entries file ASCII-armored, so it can be pasted as text
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub armor: bool,
+
+    /// How the entries are kept, chosen at `page init`
+    #[serde(default, skip_serializing_if = "is_single_file")]
+    pub layout: Layout,
+
+    /// Settings which can also be made in the user config
+    #[serde(flatten)]
+    pub config: Config,
+
+    /// An S3-compatible bucket keeping the entries file instead of the
+    /// storage folder. Last, as TOML tables follow the plain values.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub s3: Option<S3Settings>,
+}
+
+/// Where the entries file is kept in an S3-compatible bucket, the `[s3]`
+/// table of `store.toml`
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct S3Settings {
+    /// The URL of the service, e.g. `http://localhost:9000` for a MinIO
+    /// server, AWS by default
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub endpoint: Option<String>,
+
+    #[serde(default = "default_region")]
+    pub region: String,
+
+    pub bucket: String,
+
+    #[serde(default = "default_key")]
+    pub key: String,
+
+    /// The entry holding the credentials as `STORE:ENTRY`, in the fields read
+    /// by `page aws-credentials`. The `AWS_*` environment variables are used
+    /// if not set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub credentials: Option<String>,
+}
+
+fn default_region() -> String {
+    "us-east-1".to_string()
+}
+
+fn default_key() -> String {
+    ENTRIES_FILE_NAME.to_string()
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
+}
+
+fn is_single_file(layout: &Layout) -> bool {
+    *layout == Layout::SingleFile
+}
+
+impl StoreSettings {
+    /// Loads the settings of the current store, the defaults if it has none
+    pub fn load() -> Result<StoreSettings> {
+        StoreSettings::load_from(Path::new(&store_settings_file()?))
+    }
+
+    pub fn load_from(path: &Path) -> Result<StoreSettings> {
+        match fs::read_to_string(path) {
+            Ok(settings) => toml::from_str(&settings)
+                .with_context(|| format!("Failed to parse {}", path.display())),
+            Err(_) => Ok(StoreSettings::default()),
+        }
+    }
+
+    pub fn save(&self) -> Result<()> {
+        fs::write(store_settings_file()?, toml::to_string(self)?)?;
+        Ok(())
+    }
+
+    /// The key file to use, `PAGE_KEY_FILE` overrides the configured path,
+    /// e.g. when the device holding it is mounted elsewhere
+    pub fn key_file(&self) -> Option<PathBuf> {
+        self.key_file
+            .as_ref()
+            .map(|configured| match std::env::var_os("PAGE_KEY_FILE") {
+                Some(path) => PathBuf::from(path),
+                None => configured.clone(),
+            })
+    }
+}
@@ -0,0 +1,89 @@
+use crate::entries::Storage;
+use crate::utilities::to_hex;
+use age::secrecy::{ExposeSecret, SecretString};
+use age::x25519;
+use anyhow::{anyhow, Result};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+/// The salt of the fingerprint key, which only serves fingerprints
+const FINGERPRINT_SALT: &[u8] = b"page fingerprint key";
+
+/// The key fingerprints are computed with
+pub struct FingerprintKey([u8; 32]);
+
+impl FingerprintKey {
+    /// Derives the key from the passphrase and the key file of the store, if
+    /// any, with scrypt, so that guessing the passphrase from fingerprints is
+    /// slow. The parameters are fixed, so that fingerprints are the same on
+    /// every device whatever the work factor of the store.
+    pub fn derive(passphrase: &SecretString, key_file: Option<&x25519::Identity>) -> Result<Self> {
+        let mut input = passphrase.expose_secret().as_bytes().to_vec();
+        if let Some(identity) = key_file {
+            input.push(0);
+            input.extend_from_slice(identity.to_string().expose_secret().as_bytes());
+        }
+        let params = scrypt::Params::new(
+            scrypt::Params::RECOMMENDED_LOG_N,
+            scrypt::Params::RECOMMENDED_R,
+            scrypt::Params::RECOMMENDED_P,
+            32,
+        )
+        .map_err(|e| anyhow!("invalid scrypt parameters: {}", e))?;
+        let mut key = [0; 32];
+        let result = scrypt::scrypt(&input, FINGERPRINT_SALT, &params, &mut key);
+        input.iter_mut().for_each(|b| *b = 0);
+        result.map_err(|e| anyhow!("failed to derive the fingerprint key: {}", e))?;
+        Ok(FingerprintKey(key))
+    }
+}
+
+/// Identifies a secret without revealing it: an HMAC of the value, so that
+/// fingerprints of weak passwords can't be looked up or brute-forced without
+/// the key
+fn fingerprint(value: &str, key: &FingerprintKey) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(&key.0).expect("HMAC takes keys of any length");
+    mac.update(value.as_bytes());
+    to_hex(&mac.finalize().into_bytes()[..6])
+}
+
+fn quote(value: &str) -> String {
+    toml::Value::String(value.to_string()).to_string()
+}
+
+/// Lists the entries sorted by name, with fingerprints instead of passwords
+/// and field values, for `git diff` to compare revisions of the entries file.
+/// An entry without a name, read from an entry file, is listed without its
+/// section header.
+pub fn listing(storage: &Storage, key: &FingerprintKey) -> String {
+    let mut listing = vec![];
+    for (name, entry) in &storage.entries {
+        let mut lines = vec![];
+        if !name.is_empty() {
+            lines.push(format!("[{}]", name));
+        }
+        if let Some(username) = &entry.username {
+            lines.push(format!("username = {}", quote(username)));
+        }
+        if let Some(url) = &entry.url {
+            lines.push(format!("url = {}", quote(url)));
+        }
+        if let Some(env_var) = &entry.env_var {
+            lines.push(format!("env_var = {}", quote(env_var)));
+        }
+        lines.push(format!("password = {}", fingerprint(&entry.password, key)));
+        for (field, value) in &entry.fields {
+            lines.push(format!(
+                "field {} = {}",
+                quote(field),
+                fingerprint(value, key)
+            ));
+        }
+        listing.push(lines.join("\n"));
+    }
+    listing
+        .iter()
+        .map(|entry| format!("{}\n", entry))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
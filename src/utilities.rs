@@ -1,12 +1,45 @@
-use age::secrecy::SecretString;
-use anyhow::{anyhow, Error, Result};
+use crate::cli::PassphraseOptions;
+use crate::paths::{canonical_storage_dir, keyring_index_file};
+use crate::pinentry;
+use crate::session;
+use age::armor::{ArmoredReader, ArmoredWriter, Format};
+use age::secrecy::{ExposeSecret, SecretString};
+use age::x25519;
+use anyhow::{anyhow, Context, Error, Result};
 use arboard::Clipboard;
 use keyring::Keyring;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
 use std::io;
 use std::io::{Read, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-pub fn encrypt(plaintext: &[u8], passphrase: SecretString) -> Result<Vec<u8>, Error> {
-    let encryptor = age::Encryptor::with_user_passphrase(passphrase);
+/// The highest scrypt work factor (log2 of N) accepted, 2^30 needs 1 TiB of memory
+pub const MAX_WORK_FACTOR: u8 = 30;
+
+/// The environment variable allowing to decrypt with a higher work factor
+/// than expected
+pub const MAX_WORK_FACTOR_VARIABLE: &str = "PAGE_MAX_WORK_FACTOR";
+
+/// Encrypts with a passphrase. Without a work factor, age picks one taking
+/// about a second on this device.
+pub fn encrypt(
+    plaintext: &[u8],
+    passphrase: SecretString,
+    work_factor: Option<u8>,
+) -> Result<Vec<u8>, Error> {
+    let encryptor = match work_factor {
+        Some(log_n) => {
+            let mut recipient = age::scrypt::Recipient::new(passphrase);
+            recipient.set_work_factor(log_n);
+            age::Encryptor::with_recipients(std::iter::once(&recipient as _)).map_err(Error::msg)?
+        }
+        None => age::Encryptor::with_user_passphrase(passphrase),
+    };
 
     let mut encrypted = vec![];
     let mut writer = encryptor.wrap_output(&mut encrypted).map_err(Error::msg)?;
@@ -16,8 +49,68 @@ pub fn encrypt(plaintext: &[u8], passphrase: SecretString) -> Result<Vec<u8>, Er
     Ok(encrypted)
 }
 
-pub fn decrypt(encrypted: &[u8], passphrase: SecretString) -> Result<Vec<u8>, Error> {
-    let identity = age::scrypt::Identity::new(passphrase);
+const ARMOR_BEGIN: &[u8] = b"-----BEGIN AGE ENCRYPTED FILE-----";
+
+/// Converts an age file to its ASCII-armored (PEM) form
+pub fn armor(encrypted: &[u8]) -> Result<Vec<u8>> {
+    let mut writer = ArmoredWriter::wrap_output(vec![], Format::AsciiArmor)?;
+    writer.write_all(encrypted)?;
+    Ok(writer.finish()?)
+}
+
+/// Returns the binary form of an age file, which may be ASCII-armored
+pub fn dearmor(encrypted: &[u8]) -> Result<Cow<'_, [u8]>> {
+    let start = encrypted
+        .iter()
+        .position(|b| !b.is_ascii_whitespace())
+        .unwrap_or(0);
+    if !encrypted[start..].starts_with(ARMOR_BEGIN) {
+        return Ok(Cow::Borrowed(encrypted));
+    }
+    let mut binary = vec![];
+    ArmoredReader::new(&encrypted[start..])
+        .read_to_end(&mut binary)
+        .context("Failed to read ASCII-armored age file")?;
+    Ok(Cow::Owned(binary))
+}
+
+/// Returns the scrypt work factor (log2 of N) of a passphrase-encrypted age file
+pub fn scrypt_work_factor(encrypted: &[u8]) -> Option<u8> {
+    // the header is text, terminated by the line starting with `---`
+    let header = encrypted
+        .split(|b| *b == b'\n')
+        .take_while(|line| !line.starts_with(b"---"));
+    for line in header {
+        let line = std::str::from_utf8(line).ok()?;
+        if let Some(args) = line.strip_prefix("-> scrypt ") {
+            return args.split(' ').nth(1)?.parse().ok();
+        }
+    }
+    None
+}
+
+/// Decrypts with a passphrase, refusing a work factor above `max_work_factor`
+/// as the header may have been tampered with to make decrypting take very long
+pub fn decrypt(
+    encrypted: &[u8],
+    passphrase: SecretString,
+    max_work_factor: u8,
+) -> Result<Vec<u8>, Error> {
+    let mut identity = age::scrypt::Identity::new(passphrase);
+    // age only accepts work factors close to what this device does in a second,
+    // which would lock out stores configured for more work elsewhere
+    if let Some(log_n) = scrypt_work_factor(encrypted) {
+        if log_n > max_work_factor {
+            return Err(anyhow!(
+                "the scrypt work factor {} is higher than the expected {}, set {}={} to decrypt it anyway",
+                log_n,
+                max_work_factor,
+                MAX_WORK_FACTOR_VARIABLE,
+                log_n
+            ));
+        }
+        identity.set_max_work_factor(log_n);
+    }
     let decryptor = age::Decryptor::new_buffered(encrypted)?;
     let mut reader = decryptor.decrypt(Some(&identity as _).into_iter())?;
     let mut decrypted = vec![];
@@ -30,39 +123,365 @@ pub fn decrypt(encrypted: &[u8], passphrase: SecretString) -> Result<Vec<u8>, Er
     Ok(decrypted)
 }
 
-const KEYRING_APP_NAME: &str = "page";
+/// Encrypts to an age key, as used by key files and sessions
+pub fn encrypt_to_key(plaintext: &[u8], recipient: &x25519::Recipient) -> Result<Vec<u8>> {
+    let encryptor =
+        age::Encryptor::with_recipients(std::iter::once(recipient as _)).map_err(Error::msg)?;
 
-pub fn new_keyring(username: &str) -> Keyring {
-    Keyring::new(KEYRING_APP_NAME, username)
+    let mut encrypted = vec![];
+    let mut writer = encryptor.wrap_output(&mut encrypted)?;
+    writer.write_all(plaintext)?;
+    writer.finish()?;
+
+    Ok(encrypted)
 }
 
-/// Gets the passphrase from either the keyring or stdin (and stores it in the keyring)
-pub fn get_passphrase_keyring(prompt: &str) -> Result<SecretString> {
-    let username = &whoami::username();
-    let keyring = new_keyring(username);
+pub fn decrypt_with_key(encrypted: &[u8], identity: &x25519::Identity) -> Result<Vec<u8>> {
+    let decryptor = age::Decryptor::new_buffered(encrypted)?;
+    let mut reader = decryptor.decrypt(std::iter::once(identity as _))?;
+    let mut decrypted = vec![];
+    reader.read_to_end(&mut decrypted)?;
+    Ok(decrypted)
+}
 
-    let passphrase = if let Ok(pw) = keyring.get_password() {
-        SecretString::from(pw)
-    } else {
-        let passphrase = rpassword::prompt_password_stdout(prompt)?;
-        if keyring.set_password(&passphrase).is_err() {
-            return Err(anyhow!("Failed to store password in keyring"));
+/// Reads an age identity file, as written by `age-keygen` or `page init --key-file`
+pub fn read_key_file(path: &Path) -> Result<x25519::Identity> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(anyhow!(
+                "key file {} not found, the store requires it in addition to the passphrase \
+                 (set PAGE_KEY_FILE if it is somewhere else)",
+                path.display()
+            ))
         }
+        Err(e) => {
+            return Err(e).with_context(|| format!("Failed to read key file {}", path.display()))
+        }
+    };
+    contents
+        .lines()
+        .map(|line| line.trim())
+        .find(|line| line.starts_with("AGE-SECRET-KEY-"))
+        .and_then(|line| line.parse().ok())
+        .ok_or_else(|| anyhow!("{} is not an age key file", path.display()))
+}
+
+/// Writes a new age identity file, readable only by the user
+pub fn create_key_file(path: &Path) -> Result<x25519::Identity> {
+    let identity = x25519::Identity::generate();
+    let contents = format!(
+        "# public key: {}\n{}\n",
+        identity.to_public(),
+        identity.to_string().expose_secret()
+    );
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options
+        .open(path)
+        .and_then(|mut file| file.write_all(contents.as_bytes()))
+        .with_context(|| format!("Failed to create key file {}", path.display()))?;
+    Ok(identity)
+}
+
+const KEYRING_APP_NAME: &str = "page";
+
+pub fn new_keyring(account: &str) -> Keyring<'_> {
+    Keyring::new(KEYRING_APP_NAME, account)
+}
+
+/// Returns the keyring account of the current store: its canonical storage path
+pub fn keyring_account() -> Result<String> {
+    canonical_storage_dir()
+}
+
+/// Returns the accounts of all stores with a passphrase in the keyring
+pub fn keyring_accounts() -> Result<Vec<String>> {
+    match fs::read_to_string(keyring_index_file()?) {
+        Ok(index) => Ok(index.lines().map(|l| l.to_string()).collect()),
+        Err(_) => Ok(vec![]),
+    }
+}
+
+fn write_keyring_accounts(accounts: &[String]) -> Result<()> {
+    let path = keyring_index_file()?;
+    if let Some(dir) = Path::new(&path).parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let index: String = accounts
+        .iter()
+        .map(|account| format!("{}\n", account))
+        .collect();
+    fs::write(path, index)?;
+    Ok(())
+}
+
+/// Records that the keyring holds a passphrase for `account`
+fn remember_keyring_account(account: &str) -> Result<()> {
+    let mut accounts = keyring_accounts()?;
+    if !accounts.iter().any(|a| a == account) {
+        accounts.push(account.to_string());
+        write_keyring_accounts(&accounts)?;
+    }
+    Ok(())
+}
+
+/// Deletes the passphrase of `account` from the keyring
+pub fn forget_keyring_account(account: &str) -> Result<()> {
+    let deleted = new_keyring(account).delete_password().is_ok();
+    let mut accounts = keyring_accounts()?;
+    let known = accounts.iter().any(|a| a == account);
+    accounts.retain(|a| a != account);
+    write_keyring_accounts(&accounts)?;
+    if !deleted && !known {
+        return Err(anyhow!("Failed to delete password from keyring"));
+    }
+    Ok(())
+}
+
+/// The item used before passphrases were stored per store, keyed by the OS username
+pub fn legacy_keyring_account() -> String {
+    whoami::username()
+}
+
+/// Where a passphrase was obtained from
+#[derive(Clone, Copy, PartialEq)]
+pub enum PassphraseSource {
+    /// `--passphrase-file`, `--passphrase-fd` or `--passphrase-command`
+    Unattended,
+    Keyring,
+    /// The item stored by older versions of `page`, keyed by the OS username
+    LegacyKeyring,
+    /// A session created by `page unlock`, given in `PAGE_SESSION`
+    Session,
+    Prompt,
+}
+
+/// Returns the passphrase of the current store from the keyring, if any
+fn keyring_passphrase() -> Result<Option<(SecretString, PassphraseSource)>> {
+    let account = keyring_account()?;
+    if let Ok(pw) = new_keyring(&account).get_password() {
+        return Ok(Some((SecretString::from(pw), PassphraseSource::Keyring)));
+    }
+    let legacy_account = legacy_keyring_account();
+    if let Ok(pw) = new_keyring(&legacy_account).get_password() {
+        return Ok(Some((
+            SecretString::from(pw),
+            PassphraseSource::LegacyKeyring,
+        )));
+    }
+    Ok(None)
+}
+
+/// Stores the passphrase of the current store in the keyring
+pub fn store_keyring_passphrase(passphrase: &SecretString) -> Result<()> {
+    let account = keyring_account()?;
+    if new_keyring(&account)
+        .set_password(passphrase.expose_secret())
+        .is_err()
+    {
+        return Err(anyhow!("Failed to store password in keyring"));
+    }
+    remember_keyring_account(&account)
+}
 
-        SecretString::from(passphrase)
+/// Moves a passphrase stored by older versions of `page` to the store's account,
+/// once it is known to decrypt the store. The legacy item is deleted, so it
+/// isn't picked up by other stores as well.
+pub fn migrate_legacy_keyring(passphrase: &SecretString) -> Result<()> {
+    store_keyring_passphrase(passphrase)?;
+    let _ = new_keyring(&legacy_keyring_account()).delete_password();
+    Ok(())
+}
+
+/// Formats bytes as lowercase hexadecimal, e.g. a digest
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Seconds since the Unix epoch
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Returns true if decrypting failed because of a wrong passphrase
+pub fn is_wrong_passphrase(error: &Error) -> bool {
+    matches!(
+        error.downcast_ref::<age::DecryptError>(),
+        Some(age::DecryptError::DecryptionFailed) | Some(age::DecryptError::NoMatchingKeys)
+    )
+}
+
+/// Where interactive prompts are written to
+#[derive(Clone, Copy)]
+pub enum PromptOutput {
+    Stdout,
+    /// Used by commands whose standard output is consumed by other programs
+    Stderr,
+}
+
+pub fn prompt_password(prompt: &str, output: PromptOutput) -> Result<String> {
+    let password = match output {
+        PromptOutput::Stdout => rpassword::prompt_password_stdout(prompt)?,
+        PromptOutput::Stderr => rpassword::prompt_password_stderr(prompt)?,
     };
+    Ok(password)
+}
+
+const PASSPHRASE_PROMPT: &str = "Enter passphrase: ";
+
+/// Asks for the passphrase, with pinentry if configured or on the terminal otherwise
+pub fn prompt_passphrase(
+    prompt: &str,
+    unlock: &PassphraseOptions,
+    output: PromptOutput,
+) -> Result<SecretString> {
+    match &unlock.pinentry {
+        Some(program) => {
+            pinentry::get_pin(program, "Enter the passphrase of your page store", prompt)
+        }
+        None => Ok(SecretString::from(prompt_password(prompt, output)?)),
+    }
+}
+
+/// Strips the line break terminating the passphrase, if any
+fn first_line(mut passphrase: String, source: &str) -> Result<SecretString> {
+    if let Some(end) = passphrase.find(['\n', '\r']) {
+        passphrase.truncate(end);
+    }
+    if passphrase.is_empty() {
+        return Err(anyhow!("empty passphrase read from {}", source));
+    }
+    Ok(SecretString::from(passphrase))
+}
+
+#[cfg(unix)]
+fn read_fd(fd: i32) -> Result<String> {
+    use std::mem::ManuallyDrop;
+    use std::os::unix::io::FromRawFd;
 
+    // the descriptor belongs to the caller, it is read once and left open
+    let mut file = ManuallyDrop::new(unsafe { File::from_raw_fd(fd) });
+    let mut passphrase = String::new();
+    file.read_to_string(&mut passphrase)
+        .with_context(|| format!("Failed to read passphrase from file descriptor {}", fd))?;
     Ok(passphrase)
 }
 
-pub fn get_passphrase(no_keyring: bool) -> Result<SecretString> {
-    const PROMPT: &str = "Enter passphrase: ";
-    if no_keyring {
-        let passphrase = rpassword::prompt_password_stdout(PROMPT)?;
-        Ok(SecretString::from(passphrase))
+#[cfg(not(unix))]
+fn read_fd(_fd: i32) -> Result<String> {
+    Err(anyhow!("--passphrase-fd is only supported on Unix"))
+}
+
+fn run_passphrase_command(command: &str) -> Result<String> {
+    let mut shell = if cfg!(windows) {
+        let mut shell = Command::new("cmd");
+        shell.arg("/C");
+        shell
+    } else {
+        let mut shell = Command::new("sh");
+        shell.arg("-c");
+        shell
+    };
+    let output = shell
+        .arg(command)
+        .stderr(Stdio::inherit())
+        .output()
+        .with_context(|| format!("Failed to run passphrase command '{}'", command))?;
+    if !output.status.success() {
+        return Err(anyhow!("passphrase command '{}' failed", command));
+    }
+    Ok(String::from_utf8(output.stdout)?)
+}
+
+/// Passphrases read from non-interactive sources by the command, by source:
+/// a file descriptor can only be read once, and a command may ask each time
+static UNATTENDED: Mutex<BTreeMap<String, SecretString>> = Mutex::new(BTreeMap::new());
+
+/// Identifies the non-interactive passphrase source given, if any
+fn unattended_source(unlock: &PassphraseOptions) -> Option<String> {
+    if let Some(path) = &unlock.passphrase_file {
+        Some(format!("file {}", path.display()))
+    } else if let Some(fd) = unlock.passphrase_fd {
+        Some(format!("fd {}", fd))
+    } else {
+        unlock
+            .passphrase_command
+            .as_ref()
+            .map(|command| format!("command {}", command))
+    }
+}
+
+/// Gets the passphrase from a non-interactive source, if one is given. Each
+/// source is only read once per command.
+fn get_passphrase_unattended(unlock: &PassphraseOptions) -> Result<Option<SecretString>> {
+    let source = match unattended_source(unlock) {
+        Some(source) => source,
+        None => return Ok(None),
+    };
+    if let Some(passphrase) = UNATTENDED.lock().unwrap().get(&source) {
+        return Ok(Some(passphrase.clone()));
+    }
+    let passphrase = read_passphrase_unattended(unlock)?;
+    UNATTENDED
+        .lock()
+        .unwrap()
+        .insert(source, passphrase.clone());
+    Ok(Some(passphrase))
+}
+
+fn read_passphrase_unattended(unlock: &PassphraseOptions) -> Result<SecretString> {
+    let passphrase = if let Some(path) = &unlock.passphrase_file {
+        let passphrase = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read passphrase file {}", path.display()))?;
+        first_line(passphrase, "passphrase file")?
+    } else if let Some(fd) = unlock.passphrase_fd {
+        first_line(read_fd(fd)?, "file descriptor")?
+    } else if let Some(command) = &unlock.passphrase_command {
+        first_line(run_passphrase_command(command)?, "passphrase command")?
     } else {
-        get_passphrase_keyring(PROMPT)
+        return Err(anyhow!("no passphrase source given"));
+    };
+    Ok(passphrase)
+}
+
+/// Gets the passphrase, in order of precedence, from `--passphrase-file`,
+/// `--passphrase-fd`, `--passphrase-command`, `PAGE_SESSION`, the keyring or a prompt.
+/// Nothing is stored in the keyring, see `get_passphrase`.
+pub fn read_passphrase(
+    unlock: &PassphraseOptions,
+    output: PromptOutput,
+) -> Result<(SecretString, PassphraseSource)> {
+    if let Some(passphrase) = get_passphrase_unattended(unlock)? {
+        return Ok((passphrase, PassphraseSource::Unattended));
+    }
+    if let Some(passphrase) = session::passphrase(&canonical_storage_dir()?)? {
+        return Ok((passphrase, PassphraseSource::Session));
+    }
+    if !unlock.no_keyring {
+        if let Some(found) = keyring_passphrase()? {
+            return Ok(found);
+        }
+    }
+    let passphrase = prompt_passphrase(PASSPHRASE_PROMPT, unlock, output)?;
+    Ok((passphrase, PassphraseSource::Prompt))
+}
+
+/// Gets the passphrase like `read_passphrase`, storing a prompted one in the keyring
+pub fn get_passphrase(unlock: &PassphraseOptions, output: PromptOutput) -> Result<SecretString> {
+    let (passphrase, source) = read_passphrase(unlock, output)?;
+    if source == PassphraseSource::Prompt && !unlock.no_keyring {
+        store_keyring_passphrase(&passphrase)?;
     }
+    Ok(passphrase)
 }
 
 pub fn read_stdin(msg: &str) -> Result<String> {
@@ -74,12 +493,81 @@ pub fn read_stdin(msg: &str) -> Result<String> {
     Ok(entry.to_owned())
 }
 
-pub fn reveal(attribute: &String, on_screen: bool) -> Result<()> {
+pub fn reveal(attribute: &String, on_screen: bool, clipboard_timeout: u64) -> Result<()> {
     if on_screen {
         println!("{}", attribute);
+    } else if clipboard_timeout > 0 {
+        copy_and_clear(attribute, Duration::from_secs(clipboard_timeout))?;
     } else {
         let mut clipboard = Clipboard::new()?;
         clipboard.set_text(attribute.to_string())?;
     }
     Ok(())
 }
+
+/// Copies to the clipboard from a background process, which clears the
+/// clipboard after the timeout unless it was changed in the meantime
+#[cfg(unix)]
+fn copy_and_clear(value: &str, timeout: Duration) -> Result<()> {
+    use std::io::{BufRead, BufReader};
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::net::UnixStream;
+
+    // the child reports whether copying worked, an empty line on success
+    let (parent, mut child) = UnixStream::pair()?;
+    match fork::fork() {
+        Ok(fork::Fork::Parent(_)) => {
+            drop(child);
+            let mut error = String::new();
+            BufReader::new(parent).read_line(&mut error)?;
+            return match error.trim_end() {
+                "" => Ok(()),
+                error => Err(anyhow!("{}", error)),
+            };
+        }
+        Ok(fork::Fork::Child) => {}
+        Err(_) => {
+            return Err(anyhow!(
+                "failed to start clearing the clipboard in the background"
+            ))
+        }
+    }
+
+    let _ = fork::setsid();
+    // release the standard streams, e.g. a pipe read by `$(page show ...)`
+    if let Ok(null) = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")
+    {
+        for fd in 0..3 {
+            unsafe { libc::dup2(null.as_raw_fd(), fd) };
+        }
+    }
+    let copied = Clipboard::new().and_then(|mut clipboard| {
+        clipboard.set_text(value.to_string())?;
+        Ok(clipboard)
+    });
+    match copied {
+        Ok(mut clipboard) => {
+            let _ = writeln!(child);
+            drop(child);
+            std::thread::sleep(timeout);
+            if clipboard.get_text().map(|t| t == value).unwrap_or(false) {
+                let _ = clipboard.clear();
+            }
+            std::process::exit(0)
+        }
+        Err(e) => {
+            let _ = writeln!(child, "{}", e);
+            std::process::exit(1)
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn copy_and_clear(value: &str, _timeout: Duration) -> Result<()> {
+    let mut clipboard = Clipboard::new()?;
+    clipboard.set_text(value.to_string())?;
+    Ok(())
+}
@@ -1,7 +1,7 @@
 use assert_cmd::Command;
 use predicates::prelude::*;
 use predicates::str::RegexPredicate;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 fn page() -> Command {
     Command::cargo_bin("page").unwrap()
@@ -11,6 +11,57 @@ fn tempdir() -> tempfile::TempDir {
     tempfile::tempdir().unwrap()
 }
 
+/* `page` for the store in `storage`, keeping the runtime folder and the config
+file in `dir`, as a process for the tests which spawn it */
+fn page_process_in(storage: &Path, dir: &Path) -> std::process::Command {
+    let mut cmd = std::process::Command::new(assert_cmd::cargo::cargo_bin("page"));
+    cmd.env("PAGE_STORAGE_FOLDER", storage)
+        .env("PAGE_RUNTIME_FOLDER", dir)
+        .env("PAGE_CONFIG_FILE", dir.join("config.toml"))
+        .arg("--no-keyring");
+    cmd
+}
+
+fn page_in(storage: &Path, dir: &Path) -> Command {
+    Command::from_std(page_process_in(storage, dir))
+}
+
+/* the passphrase `master` in a file in `dir` */
+fn passphrase_file(dir: &Path) -> PathBuf {
+    let path = dir.join("passphrase");
+    std::fs::write(&path, "master\n").unwrap();
+    path
+}
+
+/* like `page_in`, reading the passphrase from `passphrase_file` */
+fn page_unlocked_in(storage: &Path, dir: &Path) -> Command {
+    let mut cmd = page_process_in(storage, dir);
+    cmd.env("PAGE_PASSPHRASE_FILE", passphrase_file(dir));
+    Command::from_std(cmd)
+}
+
+/* keeps git, and `page` running git, from reading the user's and the system's
+git config, with `HOME` in `dir`, and sets who commits */
+fn isolate_git<'a>(cmd: &'a mut Command, dir: &Path) -> &'a mut Command {
+    cmd.env("GIT_CONFIG_NOSYSTEM", "1")
+        .env("HOME", dir)
+        .env("GIT_AUTHOR_NAME", "page")
+        .env("GIT_AUTHOR_EMAIL", "page@example.com")
+        .env("GIT_COMMITTER_NAME", "page")
+        .env("GIT_COMMITTER_EMAIL", "page@example.com")
+}
+
+/* git in `repo`, running the drivers of `page` like `page_unlocked_in` */
+fn git_in(repo: &Path, dir: &Path) -> Command {
+    let mut cmd = Command::new("git");
+    cmd.current_dir(repo)
+        .env("PAGE_RUNTIME_FOLDER", dir)
+        .env("PAGE_CONFIG_FILE", dir.join("config.toml"))
+        .env("PAGE_PASSPHRASE_FILE", passphrase_file(dir));
+    isolate_git(&mut cmd, dir);
+    cmd
+}
+
 /* rpassword inserts a new line on windows  */
 fn enter_passphrase_and_password(entry: &str) -> RegexPredicate {
     predicate::str::is_match(format!(
@@ -63,7 +114,7 @@ fn info() {
         .stdout(
             predicate::str::starts_with("Storage file: ").and(
                 predicate::str::contains("entries.toml.age")
-                    .and(predicate::str::contains("\n").count(1).trim()),
+                    .and(predicate::str::contains("\n").count(2).trim()),
             ),
         );
 }
@@ -552,7 +603,7 @@ fn fail_edit_no_entry() {
         .env("PAGE_STORAGE_FOLDER", dir.path())
         .arg("--no-keyring")
         .arg("init")
-        .write_stdin(format!("{}", passphrase))
+        .write_stdin(passphrase.to_string())
         .assert()
         .stdout(predicate::str::contains("Enter passphrase: "))
         .success();
@@ -562,7 +613,7 @@ fn fail_edit_no_entry() {
         .arg("--no-keyring")
         .arg("edit")
         .arg("404")
-        .write_stdin(format!("{}", passphrase))
+        .write_stdin(passphrase.to_string())
         .assert()
         .failure()
         .stdout(enter_passphrase_show(""))
@@ -578,7 +629,7 @@ fn fail_remove_no_entry() {
         .env("PAGE_STORAGE_FOLDER", dir.path())
         .arg("--no-keyring")
         .arg("init")
-        .write_stdin(format!("{}", passphrase))
+        .write_stdin(passphrase.to_string())
         .assert()
         .stdout(predicate::str::contains("Enter passphrase: "))
         .success();
@@ -588,7 +639,7 @@ fn fail_remove_no_entry() {
         .arg("--no-keyring")
         .arg("remove")
         .arg("no-entry")
-        .write_stdin(format!("{}", passphrase))
+        .write_stdin(passphrase.to_string())
         .assert()
         .failure()
         .stdout(enter_passphrase_show(""))
@@ -628,3 +679,3110 @@ fn shell_completion_invalid_shell() {
             "error: invalid value 'invalid_shell' for '<SHELL>'",
         ));
 }
+
+#[test]
+fn env_export() {
+    let dir = tempdir();
+    let passphrase = "master";
+
+    page()
+        .env("PAGE_STORAGE_FOLDER", dir.path())
+        .arg("--no-keyring")
+        .arg("init")
+        .write_stdin(passphrase)
+        .assert()
+        .success();
+
+    page()
+        .env("PAGE_STORAGE_FOLDER", dir.path())
+        .arg("--no-keyring")
+        .arg("new")
+        .arg("app/db-password")
+        .write_stdin(format!("{passphrase}\nit's $ecret"))
+        .assert()
+        .success();
+
+    page()
+        .env("PAGE_STORAGE_FOLDER", dir.path())
+        .arg("--no-keyring")
+        .arg("new")
+        .arg("app/token")
+        .arg("--env-var")
+        .arg("API_TOKEN")
+        .write_stdin(format!("{passphrase}\ntok"))
+        .assert()
+        .success();
+
+    page()
+        .env("PAGE_STORAGE_FOLDER", dir.path())
+        .arg("--no-keyring")
+        .arg("new")
+        .arg("other")
+        .write_stdin(format!("{passphrase}\nunrelated"))
+        .assert()
+        .success();
+
+    for (format, expected) in [
+        ("dotenv", "DB_PASSWORD=\"it's \\$ecret\"\nAPI_TOKEN='tok'\n"),
+        (
+            "sh",
+            "export DB_PASSWORD='it'\\''s $ecret'\nexport API_TOKEN='tok'\n",
+        ),
+        (
+            "fish",
+            "set -gx DB_PASSWORD 'it\\'s $ecret'\nset -gx API_TOKEN 'tok'\n",
+        ),
+        (
+            "nushell",
+            "$env.DB_PASSWORD = r#'it's $ecret'#\n$env.API_TOKEN = r#'tok'#\n",
+        ),
+    ] {
+        page()
+            .env("PAGE_STORAGE_FOLDER", dir.path())
+            .arg("--no-keyring")
+            .arg("env")
+            .arg("app/")
+            .arg("--format")
+            .arg(format)
+            .write_stdin(passphrase)
+            .assert()
+            .success()
+            .stdout(expected);
+    }
+}
+
+#[test]
+fn fail_new_invalid_env_var() {
+    let dir = tempdir();
+    page()
+        .env("PAGE_STORAGE_FOLDER", dir.path())
+        .arg("--no-keyring")
+        .arg("new")
+        .arg("entry")
+        .arg("--env-var")
+        .arg("1-INVALID")
+        .assert()
+        .failure()
+        .stderr("Error: '1-INVALID' is not a valid environment variable name\n");
+}
+
+#[test]
+fn git_credential_helper() {
+    let dir = tempdir();
+    let passphrase = "master";
+    let request = "protocol=https\nhost=example.com\nusername=bob\n";
+
+    page()
+        .env("PAGE_STORAGE_FOLDER", dir.path())
+        .arg("--no-keyring")
+        .arg("init")
+        .write_stdin(passphrase)
+        .assert()
+        .success();
+
+    page()
+        .env("PAGE_STORAGE_FOLDER", dir.path())
+        .arg("--no-keyring")
+        .arg("git-credential")
+        .arg("store")
+        .write_stdin(format!("{request}password=s3cret\n\n{passphrase}\n"))
+        .assert()
+        .success()
+        .stdout("");
+
+    page()
+        .env("PAGE_STORAGE_FOLDER", dir.path())
+        .arg("--no-keyring")
+        .arg("list")
+        .write_stdin(passphrase)
+        .assert()
+        .stdout(enter_passphrase_show("git/example.com/bob"))
+        .success();
+
+    page()
+        .env("PAGE_STORAGE_FOLDER", dir.path())
+        .arg("--no-keyring")
+        .arg("git-credential")
+        .arg("get")
+        .write_stdin(format!(
+            "protocol=https\nhost=example.com\n\n{passphrase}\n"
+        ))
+        .assert()
+        .success()
+        .stdout("username=bob\npassword=s3cret\n");
+
+    page()
+        .env("PAGE_STORAGE_FOLDER", dir.path())
+        .arg("--no-keyring")
+        .arg("git-credential")
+        .arg("get")
+        .write_stdin(format!(
+            "protocol=https\nhost=example.org\n\n{passphrase}\n"
+        ))
+        .assert()
+        .success()
+        .stdout("");
+
+    // a password that doesn't match the stored one doesn't erase anything
+    page()
+        .env("PAGE_STORAGE_FOLDER", dir.path())
+        .arg("--no-keyring")
+        .arg("git-credential")
+        .arg("erase")
+        .write_stdin(format!("{request}password=other\n\n{passphrase}\n"))
+        .assert()
+        .success();
+
+    page()
+        .env("PAGE_STORAGE_FOLDER", dir.path())
+        .arg("--no-keyring")
+        .arg("git-credential")
+        .arg("erase")
+        .write_stdin(format!("{request}password=s3cret\n\n{passphrase}\n"))
+        .assert()
+        .success();
+
+    page()
+        .env("PAGE_STORAGE_FOLDER", dir.path())
+        .arg("--no-keyring")
+        .arg("git-credential")
+        .arg("get")
+        .write_stdin(format!("{request}\n{passphrase}\n"))
+        .assert()
+        .success()
+        .stdout("");
+}
+
+#[test]
+fn docker_credential_helper() {
+    let dir = tempdir();
+    let passphrase = "master";
+    let server = "https://registry.example.com/v1/";
+
+    page()
+        .env("PAGE_STORAGE_FOLDER", dir.path())
+        .arg("--no-keyring")
+        .arg("init")
+        .write_stdin(passphrase)
+        .assert()
+        .success();
+
+    page()
+        .env("PAGE_STORAGE_FOLDER", dir.path())
+        .arg("--no-keyring")
+        .arg("docker-credential")
+        .arg("store")
+        .write_stdin(format!(
+            "{{\"ServerURL\":\"{server}\",\"Username\":\"bob\",\"Secret\":\"s3cret\"}}\n{passphrase}\n"
+        ))
+        .assert()
+        .success()
+        .stdout("");
+
+    page()
+        .env("PAGE_STORAGE_FOLDER", dir.path())
+        .env("PAGE_DOCKER_FOLDER", "registries")
+        .arg("--no-keyring")
+        .arg("docker-credential")
+        .arg("list")
+        .write_stdin(passphrase)
+        .assert()
+        .success()
+        .stdout("{}\n");
+
+    page()
+        .env("PAGE_STORAGE_FOLDER", dir.path())
+        .arg("--no-keyring")
+        .arg("docker-credential")
+        .arg("list")
+        .write_stdin(passphrase)
+        .assert()
+        .success()
+        .stdout(format!("{{\"{server}\":\"bob\"}}\n"));
+
+    page()
+        .env("PAGE_STORAGE_FOLDER", dir.path())
+        .arg("--no-keyring")
+        .arg("docker-credential")
+        .arg("get")
+        .write_stdin(format!("registry.example.com/v1\n{passphrase}\n"))
+        .assert()
+        .success()
+        .stdout(
+            "{\"ServerURL\":\"registry.example.com/v1\",\"Username\":\"bob\",\"Secret\":\"s3cret\"}\n",
+        );
+
+    page()
+        .env("PAGE_STORAGE_FOLDER", dir.path())
+        .arg("--no-keyring")
+        .arg("docker-credential")
+        .arg("erase")
+        .write_stdin(format!("{server}\n{passphrase}\n"))
+        .assert()
+        .success();
+
+    page()
+        .env("PAGE_STORAGE_FOLDER", dir.path())
+        .arg("--no-keyring")
+        .arg("docker-credential")
+        .arg("get")
+        .write_stdin(format!("{server}\n{passphrase}\n"))
+        .assert()
+        .failure()
+        .stdout("credentials not found in native keychain\n");
+}
+
+#[test]
+fn aws_credentials() {
+    let dir = tempdir();
+    let passphrase = "master";
+    let entry = "aws/dev";
+
+    page()
+        .env("PAGE_STORAGE_FOLDER", dir.path())
+        .arg("--no-keyring")
+        .arg("init")
+        .write_stdin(passphrase)
+        .assert()
+        .success();
+
+    page()
+        .env("PAGE_STORAGE_FOLDER", dir.path())
+        .arg("--no-keyring")
+        .arg("new")
+        .arg(entry)
+        .arg("--field")
+        .arg("aws_access_key_id=AKIDEXAMPLE")
+        .write_stdin(format!("{passphrase}\npassword"))
+        .assert()
+        .success();
+
+    page()
+        .env("PAGE_STORAGE_FOLDER", dir.path())
+        .arg("--no-keyring")
+        .arg("aws-credentials")
+        .arg(entry)
+        .write_stdin(passphrase)
+        .assert()
+        .failure()
+        .stdout("")
+        .stderr(predicate::str::contains(
+            "Error: entry has no field 'aws_secret_access_key'",
+        ));
+
+    page()
+        .env("PAGE_STORAGE_FOLDER", dir.path())
+        .arg("--no-keyring")
+        .arg("edit")
+        .arg(entry)
+        .arg("--no-prompt")
+        .arg("--field")
+        .arg("aws_secret_access_key=wJalrXUtnFEMI")
+        .arg("--field")
+        .arg("token=session")
+        .write_stdin(passphrase)
+        .assert()
+        .success();
+
+    page()
+        .env("PAGE_STORAGE_FOLDER", dir.path())
+        .arg("--no-keyring")
+        .arg("show")
+        .arg(entry)
+        .arg("--on-screen")
+        .arg("--field")
+        .arg("token")
+        .write_stdin(passphrase)
+        .assert()
+        .stdout(enter_passphrase_show("session"))
+        .success();
+
+    page()
+        .env("PAGE_STORAGE_FOLDER", dir.path())
+        .arg("--no-keyring")
+        .arg("aws-credentials")
+        .arg(entry)
+        .arg("--session-token-field")
+        .arg("token")
+        .write_stdin(passphrase)
+        .assert()
+        .success()
+        .stdout(concat!(
+            r#"{"Version":1,"AccessKeyId":"AKIDEXAMPLE","#,
+            r#""SecretAccessKey":"wJalrXUtnFEMI","SessionToken":"session"}"#,
+            "\n"
+        ));
+}
+
+#[test]
+fn prompted_field() {
+    let dir = tempdir();
+    let passphrase = "master";
+
+    page()
+        .env("PAGE_STORAGE_FOLDER", dir.path())
+        .arg("--no-keyring")
+        .arg("init")
+        .write_stdin(passphrase)
+        .assert()
+        .success();
+
+    page()
+        .env("PAGE_STORAGE_FOLDER", dir.path())
+        .arg("--no-keyring")
+        .arg("new")
+        .arg("api")
+        .arg("--field")
+        .arg("key")
+        .arg("--field")
+        .arg("region=eu")
+        .write_stdin(format!("{passphrase}\npassword\ns3cret"))
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Value of field 'key': "));
+
+    page()
+        .env("PAGE_STORAGE_FOLDER", dir.path())
+        .arg("--no-keyring")
+        .arg("edit")
+        .arg("api")
+        .arg("--no-prompt")
+        .arg("--field")
+        .arg("region")
+        .write_stdin(format!("{passphrase}\nus"))
+        .assert()
+        .success();
+
+    for (field, value) in [("key", "s3cret"), ("region", "us")] {
+        page()
+            .env("PAGE_STORAGE_FOLDER", dir.path())
+            .arg("--no-keyring")
+            .arg("show")
+            .arg("api")
+            .arg("--on-screen")
+            .arg("--field")
+            .arg(field)
+            .write_stdin(passphrase)
+            .assert()
+            .success()
+            .stdout(enter_passphrase_show(value));
+    }
+
+    page()
+        .env("PAGE_STORAGE_FOLDER", dir.path())
+        .arg("--no-keyring")
+        .arg("edit")
+        .arg("api")
+        .arg("--field")
+        .arg("=value")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("expected NAME or NAME=VALUE"));
+}
+
+#[test]
+fn askpass() {
+    let dir = tempdir();
+    let passphrase = "master";
+
+    page()
+        .env("PAGE_STORAGE_FOLDER", dir.path())
+        .arg("--no-keyring")
+        .arg("init")
+        .write_stdin(passphrase)
+        .assert()
+        .success();
+
+    page()
+        .env("PAGE_STORAGE_FOLDER", dir.path())
+        .arg("--no-keyring")
+        .arg("new")
+        .arg("ssh/id_ed25519")
+        .write_stdin(format!("{passphrase}\nkeypass"))
+        .assert()
+        .success();
+
+    page()
+        .env("PAGE_STORAGE_FOLDER", dir.path())
+        .arg("--no-keyring")
+        .arg("askpass")
+        .arg("Enter passphrase for key '/home/x/.ssh/id_ed25519': ")
+        .write_stdin(passphrase)
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("Failed to read askpass rules"));
+
+    std::fs::write(
+        dir.path().join("askpass.toml"),
+        r#"
+[[rule]]
+pattern = "Enter passphrase for key '.*/([^/]+)'"
+entry = "ssh/$1"
+"#,
+    )
+    .unwrap();
+
+    page()
+        .env("PAGE_STORAGE_FOLDER", dir.path())
+        .arg("--no-keyring")
+        .arg("askpass")
+        .arg("Enter passphrase for key '/home/x/.ssh/id_ed25519': ")
+        .write_stdin(passphrase)
+        .assert()
+        .success()
+        .stdout("keypass\n");
+
+    page()
+        .env("PAGE_STORAGE_FOLDER", dir.path())
+        .arg("--no-keyring")
+        .arg("askpass")
+        .arg("Are you sure you want to continue connecting (yes/no)? ")
+        .assert()
+        .failure()
+        .stdout("")
+        .stderr(predicate::str::contains(
+            "no askpass rule matches the prompt",
+        ));
+}
+
+/* a pinentry stand-in answering GETPIN with `response` */
+#[cfg(unix)]
+fn fake_pinentry(dir: &Path, response: &str) -> String {
+    use std::os::unix::fs::PermissionsExt;
+
+    let path = dir.join("pinentry");
+    std::fs::write(
+        &path,
+        format!(
+            r#"#!/bin/sh
+echo "OK Pleased to meet you"
+while read -r cmd rest; do
+  case "$cmd" in
+    GETPIN) printf '%s\n' "{response}";;
+    BYE) echo "OK closing connection"; exit 0;;
+    *) echo "OK";;
+  esac
+done
+"#
+        ),
+    )
+    .unwrap();
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+    path.display().to_string()
+}
+
+#[cfg(unix)]
+#[test]
+fn pinentry() {
+    let dir = tempdir();
+    let bin = tempdir();
+    let pinentry = fake_pinentry(bin.path(), "D pass%25word\nOK");
+
+    page()
+        .env("PAGE_STORAGE_FOLDER", dir.path())
+        .arg("--no-keyring")
+        .arg("--pinentry")
+        .arg(&pinentry)
+        .arg("init")
+        .assert()
+        .success();
+
+    page()
+        .env("PAGE_STORAGE_FOLDER", dir.path())
+        .env("PAGE_PINENTRY", &pinentry)
+        .arg("--no-keyring")
+        .arg("list")
+        .assert()
+        .success()
+        .stdout("");
+
+    // the passphrase was decoded to `pass%word`
+    page()
+        .env("PAGE_STORAGE_FOLDER", dir.path())
+        .arg("--no-keyring")
+        .arg("list")
+        .write_stdin("pass%word")
+        .assert()
+        .success();
+
+    let cancel = fake_pinentry(bin.path(), "ERR 83886179 Operation cancelled");
+    page()
+        .env("PAGE_STORAGE_FOLDER", dir.path())
+        .arg("--no-keyring")
+        .arg("--pinentry")
+        .arg(&cancel)
+        .arg("list")
+        .assert()
+        .failure()
+        .stderr("Error: pinentry: Operation cancelled\n");
+}
+
+#[test]
+fn passphrase_sources() {
+    let dir = tempdir();
+    let file = dir.path().join("passphrase");
+    std::fs::write(&file, "master\n").unwrap();
+
+    page()
+        .env("PAGE_STORAGE_FOLDER", dir.path())
+        .arg("--passphrase-file")
+        .arg(&file)
+        .arg("init")
+        .assert()
+        .success();
+
+    page()
+        .env("PAGE_STORAGE_FOLDER", dir.path())
+        .env("PAGE_PASSPHRASE_FILE", &file)
+        .arg("new")
+        .arg("entry")
+        .write_stdin("password")
+        .assert()
+        .success()
+        .stdout(predicate::str::is_match("^Password for 'entry': (\n)?$").unwrap());
+
+    page()
+        .env("PAGE_STORAGE_FOLDER", dir.path())
+        .arg("--passphrase-command")
+        .arg("echo master")
+        .arg("show")
+        .arg("--on-screen")
+        .arg("entry")
+        .assert()
+        .success()
+        .stdout("password\n");
+
+    page()
+        .env("PAGE_STORAGE_FOLDER", dir.path())
+        .arg("--passphrase-command")
+        .arg("exit 1")
+        .arg("list")
+        .assert()
+        .failure()
+        .stderr("Error: passphrase command 'exit 1' failed\n");
+
+    #[cfg(unix)]
+    page()
+        .env("PAGE_STORAGE_FOLDER", dir.path())
+        .arg("--passphrase-fd")
+        .arg("0")
+        .arg("list")
+        .write_stdin("master")
+        .assert()
+        .success()
+        .stdout("entry\n");
+}
+
+/* the keyring items are keyed by the canonical storage folder, and the
+index listing them is kept in the data directory */
+#[cfg(target_os = "linux")]
+#[test]
+fn keyring_per_store() {
+    let dir = tempdir();
+    let data = dir.path().join("data");
+    let stores = [dir.path().join("personal"), dir.path().join("team")];
+    let accounts: Vec<String> = stores
+        .iter()
+        .map(|store| {
+            std::fs::create_dir_all(store).unwrap();
+            std::fs::canonicalize(store).unwrap().display().to_string()
+        })
+        .collect();
+    std::fs::create_dir_all(data.join("page")).unwrap();
+    std::fs::write(
+        data.join("page").join("keyring_stores"),
+        format!("{}\n{}\n", accounts[0], accounts[1]),
+    )
+    .unwrap();
+    let keyring = |store: &Path| {
+        let mut cmd = page();
+        cmd.env("XDG_DATA_HOME", &data)
+            .env("PAGE_STORAGE_FOLDER", store)
+            .arg("keyring");
+        cmd
+    };
+
+    keyring(&stores[0])
+        .arg("check")
+        .arg("--all")
+        .assert()
+        .success()
+        .stdout(format!(
+            "{}: failed to access password in keyring\n{}: failed to access password in keyring\n",
+            accounts[0], accounts[1]
+        ));
+    keyring(&stores[0])
+        .arg("check")
+        .assert()
+        .failure()
+        .stderr("Error: Failed to access password in keyring\n");
+
+    // forgetting a store only drops its own item
+    keyring(&stores[0]).arg("forget").assert().success();
+    keyring(&stores[1])
+        .arg("check")
+        .arg("--all")
+        .assert()
+        .success()
+        .stdout(format!(
+            "{}: failed to access password in keyring\n",
+            accounts[1]
+        ));
+    keyring(&stores[0])
+        .arg("forget")
+        .assert()
+        .failure()
+        .stderr("Error: Failed to delete password from keyring\n");
+
+    keyring(&stores[0])
+        .arg("forget")
+        .arg("--all")
+        .assert()
+        .success();
+    keyring(&stores[1])
+        .arg("check")
+        .arg("--all")
+        .assert()
+        .success()
+        .stdout("No store has a passphrase in the keyring\n");
+}
+
+/* like keyring_per_store, expects no keyring service to be reachable, so that
+using the keyring fails */
+#[cfg(target_os = "linux")]
+#[test]
+fn keyring_overrides_config() {
+    let dir = tempdir();
+    let config = dir.path().join("config.toml");
+    std::fs::write(&config, "keyring = false\n").unwrap();
+    let page_in_store = || {
+        let mut cmd = page();
+        cmd.env("PAGE_STORAGE_FOLDER", dir.path().join("store"))
+            .env("PAGE_RUNTIME_FOLDER", dir.path())
+            .env("PAGE_CONFIG_FILE", &config)
+            .env("XDG_DATA_HOME", dir.path().join("data"));
+        cmd
+    };
+
+    page_in_store()
+        .arg("init")
+        .write_stdin("master")
+        .assert()
+        .success();
+    page_in_store()
+        .arg("list")
+        .write_stdin("master")
+        .assert()
+        .success();
+    page_in_store()
+        .arg("--keyring")
+        .arg("list")
+        .write_stdin("master")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "Failed to store password in keyring",
+        ));
+    page_in_store()
+        .arg("--keyring")
+        .arg("--no-keyring")
+        .arg("list")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "the argument '--keyring' cannot be used with '--no-keyring'",
+        ));
+}
+
+#[test]
+fn fail_keyring_set_wrong_passphrase() {
+    let dir = tempdir();
+    let file = dir.path().join("passphrase");
+    std::fs::write(&file, "master").unwrap();
+
+    page()
+        .env("PAGE_STORAGE_FOLDER", dir.path())
+        .arg("--passphrase-file")
+        .arg(&file)
+        .arg("init")
+        .assert()
+        .success();
+
+    std::fs::write(&file, "wrong").unwrap();
+    page()
+        .env("PAGE_STORAGE_FOLDER", dir.path())
+        .arg("--passphrase-file")
+        .arg(&file)
+        .arg("keyring")
+        .arg("set")
+        .assert()
+        .failure()
+        .stderr(predicate::str::starts_with(
+            "Error: Failed to decrypt entries file",
+        ));
+}
+
+#[cfg(unix)]
+#[test]
+fn agent() {
+    let dir = tempdir();
+    // created by the agent, only accessible by the user
+    let runtime = dir.path().join("runtime");
+    let socket = runtime.join("agent.sock");
+    let storage = dir.path().join("store");
+
+    page()
+        .env("PAGE_STORAGE_FOLDER", &storage)
+        .arg("--no-keyring")
+        .arg("init")
+        .write_stdin("master")
+        .assert()
+        .success();
+
+    let mut agent = std::process::Command::new(assert_cmd::cargo::cargo_bin("page"))
+        .env("PAGE_RUNTIME_FOLDER", &runtime)
+        .arg("agent")
+        .arg("--foreground")
+        .stdout(std::process::Stdio::null())
+        .spawn()
+        .unwrap();
+    for _ in 0..50 {
+        if socket.exists() {
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+
+    let with_agent = || {
+        let mut cmd = page();
+        cmd.env("PAGE_STORAGE_FOLDER", &storage)
+            .env("PAGE_RUNTIME_FOLDER", &runtime)
+            .arg("--no-keyring");
+        cmd
+    };
+
+    with_agent()
+        .arg("new")
+        .arg("entry")
+        .write_stdin("master\npassword")
+        .assert()
+        .success()
+        .stdout(enter_passphrase_and_password("entry"));
+
+    // unlocked in the agent, the passphrase isn't asked for
+    with_agent()
+        .arg("new")
+        .arg("other")
+        .write_stdin("secret")
+        .assert()
+        .success()
+        .stdout(predicate::str::is_match("^Password for 'other': (\n)?$").unwrap());
+
+    with_agent()
+        .arg("show")
+        .arg("--on-screen")
+        .arg("other")
+        .assert()
+        .success()
+        .stdout("secret\n");
+
+    with_agent()
+        .arg("info")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(
+            "Agent: running, 1 unlocked store(s)",
+        ));
+
+    with_agent()
+        .arg("lock")
+        .assert()
+        .success()
+        .stdout("Agent locked\n");
+
+    with_agent()
+        .arg("show")
+        .arg("--on-screen")
+        .arg("other")
+        .write_stdin("master")
+        .assert()
+        .success()
+        .stdout(enter_passphrase_show("secret"));
+
+    with_agent()
+        .arg("agent")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("an agent is already listening"));
+
+    agent.kill().unwrap();
+    agent.wait().unwrap();
+
+    with_agent()
+        .arg("lock")
+        .assert()
+        .success()
+        .stdout("Agent is not running\n");
+}
+
+#[cfg(unix)]
+#[test]
+fn agent_socket_folder_not_private() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = tempdir();
+    let runtime = dir.path().join("runtime");
+    std::fs::create_dir(&runtime).unwrap();
+    std::fs::set_permissions(&runtime, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    page()
+        .env("PAGE_RUNTIME_FOLDER", &runtime)
+        .arg("agent")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "of the agent socket should be owned by the current user with mode 0700",
+        ));
+
+    // e.g. a socket planted by another user in a shared folder
+    let _listener = std::os::unix::net::UnixListener::bind(runtime.join("agent.sock")).unwrap();
+    page()
+        .env("PAGE_RUNTIME_FOLDER", &runtime)
+        .arg("lock")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "of the agent socket should be owned by the current user with mode 0700",
+        ));
+}
+
+#[test]
+fn session() {
+    let dir = tempdir();
+    let storage = dir.path().join("store");
+    let runtime = dir.path().join("runtime");
+    let file = dir.path().join("passphrase");
+    std::fs::write(&file, "master").unwrap();
+
+    let page_in_store = || {
+        let mut cmd = page();
+        cmd.env("PAGE_STORAGE_FOLDER", &storage)
+            .env("PAGE_RUNTIME_FOLDER", &runtime)
+            .env_remove("PAGE_SESSION")
+            .arg("--no-keyring");
+        cmd
+    };
+
+    page_in_store()
+        .arg("--passphrase-file")
+        .arg(&file)
+        .arg("init")
+        .assert()
+        .success();
+    page_in_store()
+        .arg("--passphrase-file")
+        .arg(&file)
+        .arg("new")
+        .arg("entry")
+        .write_stdin("password")
+        .assert()
+        .success();
+
+    page_in_store()
+        .arg("unlock")
+        .write_stdin("wrong")
+        .assert()
+        .failure();
+
+    let output = page_in_store()
+        .arg("unlock")
+        .write_stdin("master")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let token = stdout
+        .strip_prefix("export PAGE_SESSION='")
+        .and_then(|rest| rest.strip_suffix("'\n"))
+        .unwrap()
+        .to_string();
+    assert!(token.starts_with("AGE-SECRET-KEY-"));
+
+    page_in_store()
+        .env("PAGE_SESSION", &token)
+        .arg("show")
+        .arg("--on-screen")
+        .arg("entry")
+        .assert()
+        .success()
+        .stdout("password\n");
+
+    // a session of another store is ignored
+    page()
+        .env("PAGE_STORAGE_FOLDER", dir.path().join("other"))
+        .env("PAGE_RUNTIME_FOLDER", &runtime)
+        .env("PAGE_SESSION", &token)
+        .arg("--no-keyring")
+        .arg("init")
+        .write_stdin("other")
+        .assert()
+        .success();
+
+    page_in_store()
+        .env("PAGE_SESSION", "invalid")
+        .arg("list")
+        .assert()
+        .failure()
+        .stderr("Error: PAGE_SESSION is not a valid session token\n");
+
+    page_in_store()
+        .arg("lock")
+        .assert()
+        .success()
+        .stdout(predicate::str::starts_with("Invalidated 1 session(s)\n"));
+
+    page_in_store()
+        .env("PAGE_SESSION", &token)
+        .arg("list")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("session expired or locked"));
+
+    let output = page_in_store()
+        .arg("--passphrase-file")
+        .arg(&file)
+        .arg("unlock")
+        .arg("--expiry")
+        .arg("0")
+        .arg("--format")
+        .arg("dotenv")
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let token = stdout.trim().strip_prefix("PAGE_SESSION=").unwrap();
+
+    page_in_store()
+        .env("PAGE_SESSION", token.trim_matches('\''))
+        .arg("list")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("session expired"));
+}
+
+#[test]
+fn key_file() {
+    let dir = tempdir();
+    let storage = dir.path().join("store");
+    let key_file = dir.path().join("usb").join("page.key");
+    std::fs::create_dir(dir.path().join("usb")).unwrap();
+
+    let page_in_store = || {
+        let mut cmd = page_in(&storage, dir.path());
+        cmd.env_remove("PAGE_KEY_FILE");
+        cmd
+    };
+
+    page_in_store()
+        .arg("init")
+        .arg("--key-file")
+        .arg(&key_file)
+        .write_stdin("master")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Created key file"));
+    assert!(std::fs::read_to_string(&key_file)
+        .unwrap()
+        .contains("AGE-SECRET-KEY-"));
+
+    // passphrase and key file
+    page_in_store()
+        .arg("new")
+        .arg("entry")
+        .write_stdin("master\npassword")
+        .assert()
+        .success();
+    page_in_store()
+        .arg("show")
+        .arg("--on-screen")
+        .arg("entry")
+        .write_stdin("master")
+        .assert()
+        .success()
+        .stdout(enter_passphrase_show("password"));
+
+    // the passphrase alone is not enough
+    let moved = dir.path().join("page.key");
+    std::fs::rename(&key_file, &moved).unwrap();
+    page_in_store()
+        .arg("list")
+        .write_stdin("master")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("key file").and(predicate::str::contains("not found")));
+
+    // the key file alone is not enough
+    page_in_store()
+        .env("PAGE_KEY_FILE", &moved)
+        .arg("list")
+        .write_stdin("wrong")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("Failed to decrypt entries file"));
+
+    // PAGE_KEY_FILE overrides the configured path
+    page_in_store()
+        .env("PAGE_KEY_FILE", &moved)
+        .arg("list")
+        .write_stdin("master")
+        .assert()
+        .success()
+        .stdout(enter_passphrase_show("entry"));
+
+    // another key file doesn't match
+    let other = dir.path().join("other.key");
+    page()
+        .env("PAGE_STORAGE_FOLDER", dir.path().join("other"))
+        .arg("--no-keyring")
+        .arg("init")
+        .arg("--key-file")
+        .arg(&other)
+        .write_stdin("master")
+        .assert()
+        .success();
+    page_in_store()
+        .env("PAGE_KEY_FILE", &other)
+        .arg("list")
+        .write_stdin("master")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("doesn't match the store"));
+
+    page_in_store()
+        .arg("init")
+        .arg("--key-file")
+        .arg(&other)
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("already exists"));
+}
+
+#[test]
+fn work_factor() {
+    let dir = tempdir();
+    let page_in_store = || page_in(dir.path(), dir.path());
+
+    page_in_store()
+        .arg("init")
+        .arg("--work-factor")
+        .arg("0")
+        .assert()
+        .failure();
+
+    page_in_store()
+        .arg("init")
+        .arg("--work-factor")
+        .arg("10")
+        .write_stdin("master")
+        .assert()
+        .success();
+    page_in_store()
+        .arg("new")
+        .arg("entry")
+        .write_stdin("master\npassword")
+        .assert()
+        .success();
+    page_in_store().arg("info").assert().success().stdout(
+        predicate::str::contains("Scrypt work factor: 10\n").and(predicate::str::contains(
+            "Configured scrypt work factor: 10\n",
+        )),
+    );
+
+    page_in_store()
+        .arg("passwd")
+        .arg("--work-factor")
+        .arg("12")
+        .write_stdin("master\nnew\nother")
+        .assert()
+        .failure()
+        .stderr("Error: the passphrases don't match\n");
+
+    page_in_store()
+        .arg("passwd")
+        .arg("--work-factor")
+        .arg("12")
+        .write_stdin("master\nnew\nnew")
+        .assert()
+        .success()
+        .stdout(predicate::str::ends_with("Passphrase changed\n"));
+    page_in_store()
+        .arg("info")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Scrypt work factor: 12\n"));
+    page_in_store()
+        .arg("show")
+        .arg("--on-screen")
+        .arg("entry")
+        .write_stdin("new")
+        .assert()
+        .success()
+        .stdout(enter_passphrase_show("password"))
+        .stderr("");
+
+    // more work than configured for the store is refused unless allowed
+    std::fs::write(dir.path().join("store.toml"), "work_factor = 10\n").unwrap();
+    page_in_store()
+        .arg("list")
+        .write_stdin("new")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "the scrypt work factor 12 is higher than the expected 10, set PAGE_MAX_WORK_FACTOR=12 to decrypt it anyway",
+        ));
+    page_in_store()
+        .env("PAGE_MAX_WORK_FACTOR", "12")
+        .arg("list")
+        .write_stdin("new")
+        .assert()
+        .success();
+    page_in_store()
+        .env("PAGE_MAX_WORK_FACTOR", "31")
+        .arg("list")
+        .write_stdin("new")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "invalid value for PAGE_MAX_WORK_FACTOR, expected at most 30",
+        ));
+}
+
+#[test]
+fn armor() {
+    let dir = tempdir();
+    let entries_file = dir.path().join("entries.toml.age");
+    let page_in_store = || page_in(dir.path(), dir.path());
+
+    page_in_store()
+        .arg("init")
+        .arg("--armor")
+        .arg("--work-factor")
+        .arg("10")
+        .write_stdin("master")
+        .assert()
+        .success();
+    assert!(std::fs::read_to_string(&entries_file)
+        .unwrap()
+        .starts_with("-----BEGIN AGE ENCRYPTED FILE-----\n"));
+
+    page_in_store()
+        .arg("new")
+        .arg("entry")
+        .write_stdin("master\npassword")
+        .assert()
+        .success();
+    let armored = std::fs::read_to_string(&entries_file).unwrap();
+    assert!(armored.starts_with("-----BEGIN AGE ENCRYPTED FILE-----\n"));
+    assert!(armored.ends_with("-----END AGE ENCRYPTED FILE-----\n"));
+    page_in_store()
+        .arg("info")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Scrypt work factor: 10\n"));
+
+    // armor is detected on load, whatever the setting
+    std::fs::write(dir.path().join("store.toml"), "work_factor = 10\n").unwrap();
+    page_in_store()
+        .arg("show")
+        .arg("--on-screen")
+        .arg("entry")
+        .write_stdin("master")
+        .assert()
+        .success()
+        .stdout(enter_passphrase_show("password"));
+    page_in_store()
+        .arg("remove")
+        .arg("entry")
+        .write_stdin("master")
+        .assert()
+        .success();
+    assert!(std::fs::read(&entries_file)
+        .unwrap()
+        .starts_with(b"age-encryption.org/v1\n"));
+
+    page_in_store()
+        .arg("init")
+        .arg("--armor")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("set `armor = true`"));
+}
+
+#[test]
+fn config() {
+    let dir = tempdir();
+    let storage = dir.path().join("store");
+    let page_in_store = || {
+        let mut cmd = page_in(&storage, dir.path());
+        cmd.env_remove("PAGE_ENV_FORMAT");
+        cmd
+    };
+
+    page_in_store()
+        .arg("config")
+        .arg("set")
+        .arg("env_format")
+        .arg("sh")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("storage not initialized"));
+    page_in_store()
+        .arg("config")
+        .arg("set")
+        .arg("--user")
+        .arg("env_format")
+        .arg("sh")
+        .assert()
+        .success();
+
+    page_in_store()
+        .arg("init")
+        .write_stdin("master")
+        .assert()
+        .success();
+    page_in_store()
+        .arg("new")
+        .arg("app/token")
+        .write_stdin("master\ntok")
+        .assert()
+        .success();
+
+    let env = || {
+        let mut cmd = page_in_store();
+        cmd.arg("env").arg("app/").write_stdin("master");
+        cmd
+    };
+    env().assert().success().stdout("export TOKEN='tok'\n");
+
+    // the store config takes precedence over the user config
+    page_in_store()
+        .arg("config")
+        .arg("set")
+        .arg("env_format")
+        .arg("fish")
+        .assert()
+        .success();
+    env().assert().success().stdout("set -gx TOKEN 'tok'\n");
+
+    // environment variables and options take precedence over the config
+    env()
+        .env("PAGE_ENV_FORMAT", "dotenv")
+        .assert()
+        .success()
+        .stdout("TOKEN='tok'\n");
+    env()
+        .env("PAGE_ENV_FORMAT", "dotenv")
+        .arg("--format")
+        .arg("sh")
+        .assert()
+        .success()
+        .stdout("export TOKEN='tok'\n");
+
+    page_in_store()
+        .arg("config")
+        .arg("get")
+        .arg("env_format")
+        .assert()
+        .success()
+        .stdout("fish\n");
+    page_in_store()
+        .arg("config")
+        .arg("list")
+        .assert()
+        .success()
+        .stdout("store\tenv_format = \"fish\"\nuser\tenv_format = \"sh\"\n");
+
+    page_in_store()
+        .arg("config")
+        .arg("unset")
+        .arg("env_format")
+        .assert()
+        .success();
+    page_in_store()
+        .arg("config")
+        .arg("get")
+        .arg("env_format")
+        .assert()
+        .success()
+        .stdout("sh\n");
+
+    page_in_store()
+        .arg("config")
+        .arg("set")
+        .arg("hooks_dir")
+        .arg("my-hooks")
+        .assert()
+        .success();
+    page_in_store()
+        .arg("info")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("my-hooks"));
+
+    page_in_store()
+        .arg("config")
+        .arg("set")
+        .arg("env_format")
+        .arg("xml")
+        .assert()
+        .failure()
+        .stderr("Error: invalid value for 'env_format'\n");
+    page_in_store()
+        .arg("config")
+        .arg("set")
+        .arg("--user")
+        .arg("armor")
+        .arg("true")
+        .assert()
+        .failure()
+        .stderr("Error: 'armor' can only be set for a store\n");
+    page_in_store()
+        .arg("config")
+        .arg("get")
+        .arg("editor")
+        .assert()
+        .failure()
+        .stderr("Error: unknown config key 'editor'\n");
+}
+
+#[test]
+fn stores() {
+    let dir = tempdir();
+    let page_with_config = || {
+        let mut cmd = page();
+        cmd.env_remove("PAGE_STORAGE_FOLDER")
+            .env_remove("PAGE_STORE")
+            .env("PAGE_RUNTIME_FOLDER", dir.path())
+            .env("PAGE_CONFIG_FILE", dir.path().join("config.toml"))
+            .arg("--no-keyring");
+        cmd
+    };
+    let personal = dir.path().join("personal");
+    let work = dir.path().join("work");
+
+    page_with_config()
+        .arg("store")
+        .arg("add")
+        .arg("personal")
+        .arg(&personal)
+        .arg("--default")
+        .assert()
+        .success();
+    page_with_config()
+        .arg("store")
+        .arg("add")
+        .arg("work")
+        .arg(&work)
+        .assert()
+        .success();
+    page_with_config()
+        .arg("store")
+        .arg("add")
+        .arg("work")
+        .arg(&work)
+        .assert()
+        .failure()
+        .stderr("Error: store 'work' already exists\n");
+    page_with_config()
+        .arg("store")
+        .arg("add")
+        .arg("a:b")
+        .arg(&work)
+        .assert()
+        .failure();
+
+    page_with_config()
+        .arg("init")
+        .write_stdin("home")
+        .assert()
+        .success();
+    page_with_config()
+        .arg("--store")
+        .arg("work")
+        .arg("init")
+        .write_stdin("office")
+        .assert()
+        .success();
+    assert!(personal.join("entries.toml.age").exists());
+    assert!(work.join("entries.toml.age").exists());
+
+    page_with_config()
+        .arg("--store")
+        .arg("work")
+        .arg("new")
+        .arg("vpn")
+        .write_stdin("office\nsecret")
+        .assert()
+        .success();
+    page_with_config()
+        .arg("list")
+        .write_stdin("home")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("vpn").not());
+    page_with_config()
+        .env("PAGE_STORE", "work")
+        .arg("list")
+        .write_stdin("office")
+        .assert()
+        .success()
+        .stdout(predicate::str::ends_with("vpn\n"));
+
+    page_with_config()
+        .arg("store")
+        .arg("list")
+        .assert()
+        .success()
+        .stdout(format!(
+            "* personal\t{}\n  work\t{}\n",
+            personal.display(),
+            work.display()
+        ));
+    page_with_config()
+        .arg("store")
+        .arg("default")
+        .arg("work")
+        .assert()
+        .success();
+    page_with_config()
+        .arg("info")
+        .assert()
+        .success()
+        .stdout(predicate::str::starts_with("Store: work\n"));
+
+    page_with_config()
+        .arg("store")
+        .arg("remove")
+        .arg("work")
+        .assert()
+        .success();
+    page_with_config()
+        .arg("--store")
+        .arg("work")
+        .arg("list")
+        .assert()
+        .failure()
+        .stderr("Error: unknown store 'work', see `page store list`\n");
+    assert!(work.join("entries.toml.age").exists());
+}
+
+#[test]
+fn copy_and_move_between_stores() {
+    let dir = tempdir();
+    let page_with_config = || {
+        let mut cmd = page();
+        cmd.env_remove("PAGE_STORAGE_FOLDER")
+            .env_remove("PAGE_STORE")
+            .env("PAGE_RUNTIME_FOLDER", dir.path())
+            .env("PAGE_CONFIG_FILE", dir.path().join("config.toml"))
+            .arg("--no-keyring");
+        cmd
+    };
+    for (name, passphrase) in [("personal", "home"), ("work", "office")] {
+        page_with_config()
+            .arg("store")
+            .arg("add")
+            .arg(name)
+            .arg(dir.path().join(name))
+            .assert()
+            .success();
+        page_with_config()
+            .arg("--store")
+            .arg(name)
+            .arg("init")
+            .write_stdin(passphrase)
+            .assert()
+            .success();
+    }
+    page_with_config()
+        .arg("--store")
+        .arg("personal")
+        .arg("new")
+        .arg("vpn")
+        .arg("--username")
+        .arg("me")
+        .write_stdin("home\nsecret")
+        .assert()
+        .success();
+
+    let list = |store: &str, passphrase: &str| {
+        let stdout = page_with_config()
+            .arg("--store")
+            .arg(store)
+            .arg("list")
+            .write_stdin(passphrase.to_string())
+            .assert()
+            .success()
+            .get_output()
+            .stdout
+            .clone();
+        String::from_utf8(stdout).unwrap()
+    };
+
+    // within a store
+    page_with_config()
+        .arg("--store")
+        .arg("personal")
+        .arg("cp")
+        .arg("vpn")
+        .arg("vpn-backup")
+        .write_stdin("home")
+        .assert()
+        .success();
+    assert!(list("personal", "home").contains("vpn-backup\n"));
+
+    // to another store, which is encrypted with its own passphrase
+    page_with_config()
+        .arg("cp")
+        .arg("personal:vpn")
+        .arg("work:")
+        .write_stdin("home\noffice")
+        .assert()
+        .success();
+    page_with_config()
+        .arg("--store")
+        .arg("work")
+        .arg("show")
+        .arg("vpn")
+        .arg("--on-screen")
+        .arg("--attribute")
+        .arg("username")
+        .write_stdin("office")
+        .assert()
+        .success()
+        .stdout(predicate::str::ends_with("me\n"));
+
+    // declining to overwrite leaves both stores unchanged
+    page_with_config()
+        .arg("mv")
+        .arg("personal:vpn-backup")
+        .arg("work:vpn")
+        .write_stdin("home\noffice\nn")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Entry 'vpn' already exists"));
+    assert!(list("personal", "home").contains("vpn-backup\n"));
+
+    page_with_config()
+        .arg("mv")
+        .arg("personal:vpn-backup")
+        .arg("work:vpn")
+        .write_stdin("home\noffice\ny")
+        .assert()
+        .success();
+    assert!(!list("personal", "home").contains("vpn-backup"));
+
+    page_with_config()
+        .arg("mv")
+        .arg("personal:missing")
+        .arg("work:")
+        .write_stdin("home")
+        .assert()
+        .failure()
+        .stderr("Error: entry 'missing' not found\n");
+    page_with_config()
+        .arg("mv")
+        .arg("work:vpn")
+        .arg("work:")
+        .assert()
+        .failure()
+        .stderr("Error: source and destination are the same entry\n");
+}
+
+#[cfg(unix)]
+#[test]
+fn move_between_stores_with_passphrase_fds() {
+    let dir = tempdir();
+    let config = dir.path().join("config.toml");
+    let page_with_config = || {
+        let mut cmd = page();
+        cmd.env_remove("PAGE_STORAGE_FOLDER")
+            .env_remove("PAGE_STORE")
+            .env("PAGE_RUNTIME_FOLDER", dir.path())
+            .env("PAGE_CONFIG_FILE", &config)
+            .arg("--no-keyring");
+        cmd
+    };
+    // the passphrases of both stores are handed over as file descriptors 3 and 4
+    let page_with_fds = |args: &str| {
+        let mut cmd = std::process::Command::new("sh");
+        cmd.arg("-c")
+            .arg(format!(
+                "exec \"$0\" --no-keyring {} 3<\"$1\" 4<\"$2\"",
+                args
+            ))
+            .arg(assert_cmd::cargo::cargo_bin("page"))
+            .arg(dir.path().join("personal.pass"))
+            .arg(dir.path().join("work.pass"))
+            .env_remove("PAGE_STORAGE_FOLDER")
+            .env_remove("PAGE_STORE")
+            .env("PAGE_RUNTIME_FOLDER", dir.path())
+            .env("PAGE_CONFIG_FILE", &config);
+        Command::from_std(cmd)
+    };
+    for (name, passphrase) in [("personal", "home"), ("work", "office")] {
+        std::fs::write(dir.path().join(format!("{}.pass", name)), passphrase).unwrap();
+        page_with_config()
+            .arg("store")
+            .arg("add")
+            .arg(name)
+            .arg(dir.path().join(name))
+            .assert()
+            .success();
+        page_with_config()
+            .arg("--store")
+            .arg(name)
+            .arg("init")
+            .write_stdin(passphrase)
+            .assert()
+            .success();
+    }
+    for entry in ["vpn", "wifi"] {
+        page_with_config()
+            .arg("--store")
+            .arg("personal")
+            .arg("new")
+            .arg(entry)
+            .write_stdin(format!("home\n{}-secret", entry))
+            .assert()
+            .success();
+    }
+
+    page_with_fds("--passphrase-fd 3 mv personal:vpn work: --to-passphrase-fd 4")
+        .assert()
+        .success()
+        .stdout("");
+
+    // the passphrase of the source store isn't used for the destination store
+    page_with_fds("--passphrase-fd 3 cp personal:wifi work:")
+        .write_stdin("office")
+        .assert()
+        .success()
+        .stdout(predicate::str::starts_with("Enter passphrase: "));
+    page_with_fds("--passphrase-fd 3 cp personal:wifi work:wifi2 --to-passphrase-fd 3")
+        .assert()
+        .failure()
+        .stderr(predicate::str::starts_with(
+            "Error: Failed to decrypt entries file",
+        ));
+
+    page_with_fds("--store personal --passphrase-fd 3 list")
+        .assert()
+        .success()
+        .stdout("wifi\n");
+    page_with_fds("--store work --passphrase-fd 4 list")
+        .assert()
+        .success()
+        .stdout("vpn\nwifi\n");
+}
+
+#[test]
+fn merge() {
+    let dir = tempdir();
+    let ours = dir.path().join("ours");
+    let theirs = dir.path().join("theirs");
+    let page_in = |storage: &Path| {
+        let mut cmd = page();
+        cmd.env("PAGE_STORAGE_FOLDER", storage)
+            .env("PAGE_RUNTIME_FOLDER", dir.path())
+            .arg("--no-keyring");
+        cmd
+    };
+    let new = |storage: &Path, entry: &str, password: &str| {
+        page_in(storage)
+            .arg("new")
+            .arg(entry)
+            .write_stdin(format!("master\n{}", password))
+            .assert()
+            .success();
+    };
+    let edit = |storage: &Path, entry: &str, password: &str| {
+        page_in(storage)
+            .arg("edit")
+            .arg(entry)
+            .write_stdin(format!("master\n{}", password))
+            .assert()
+            .success();
+    };
+    let copy_store = |to: &Path| {
+        std::fs::create_dir_all(to).unwrap();
+        std::fs::copy(ours.join("entries.toml.age"), to.join("entries.toml.age")).unwrap();
+    };
+
+    page_in(&ours)
+        .arg("init")
+        .write_stdin("master")
+        .assert()
+        .success();
+    for entry in ["a", "b", "c"] {
+        new(&ours, entry, entry);
+    }
+    let base = dir.path().join("base");
+    copy_store(&base);
+    copy_store(&theirs);
+
+    new(&ours, "d", "d");
+    edit(&ours, "b", "b-ours");
+    new(&theirs, "e", "e");
+    edit(&theirs, "a", "a-theirs");
+    edit(&theirs, "b", "b-theirs");
+    page_in(&theirs)
+        .arg("remove")
+        .arg("c")
+        .write_stdin("master")
+        .assert()
+        .success();
+
+    // the store is unchanged unless the conflict is resolved
+    page_in(&ours)
+        .arg("merge")
+        .arg(theirs.join("entries.toml.age"))
+        .arg("--base")
+        .arg(base.join("entries.toml.age"))
+        .write_stdin("master\nq")
+        .assert()
+        .failure()
+        .stdout(predicate::str::contains(
+            "Entry 'b' was changed in the store and changed in the other file. Keep (o)urs or (t)heirs?",
+        ))
+        .stderr("Error: merge aborted, the store is unchanged\n");
+
+    page_in(&ours)
+        .arg("merge")
+        .arg(theirs.join("entries.toml.age"))
+        .arg("--base")
+        .arg(base.join("entries.toml.age"))
+        .arg("--prefer")
+        .arg("theirs")
+        .write_stdin("master")
+        .assert()
+        .success()
+        .stdout(predicate::str::ends_with(
+            "Added 'e'\nUpdated 'a'\nRemoved 'c'\nConflict on 'b', kept theirs\nMerged 3 change(s), resolved 1 conflict(s)\n",
+        ));
+
+    let show = |entry: &str| {
+        page_in(&ours)
+            .arg("show")
+            .arg("--on-screen")
+            .arg(entry)
+            .write_stdin("master")
+            .assert()
+    };
+    show("a")
+        .success()
+        .stdout(predicate::str::ends_with("a-theirs\n"));
+    show("b")
+        .success()
+        .stdout(predicate::str::ends_with("b-theirs\n"));
+    show("c").failure();
+    show("d").success().stdout(predicate::str::ends_with("d\n"));
+    show("e").success().stdout(predicate::str::ends_with("e\n"));
+
+    // without a base, entries missing on one side are kept, and entries
+    // changed on both sides are conflicts
+    page_in(&ours)
+        .arg("merge")
+        .arg(base.join("entries.toml.age"))
+        .write_stdin("master\nq")
+        .assert()
+        .failure()
+        .stdout(predicate::str::contains(
+            "Entry 'a' was changed in the store and changed in the other file. Keep (o)urs or (t)heirs?",
+        ));
+    page_in(&ours)
+        .arg("merge")
+        .arg(base.join("entries.toml.age"))
+        .arg("--prefer")
+        .arg("ours")
+        .write_stdin("master")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Added 'c'\n"))
+        .stdout(predicate::str::contains("Conflict on 'b', kept ours\n"))
+        .stdout(predicate::str::contains("Removed").not());
+    page_in(&ours)
+        .arg("merge")
+        .arg(base.join("entries.toml.age"))
+        .arg("--prefer")
+        .arg("newer")
+        .write_stdin("master")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Conflict on 'a', kept ours\n"))
+        .stdout(predicate::str::contains("Updated").not());
+}
+
+#[test]
+fn git_merge_driver() {
+    let dir = tempdir();
+    let storage = dir.path().join("store");
+    let page_in_store = || page_unlocked_in(&storage, dir.path());
+    let git = |args: &[&str]| {
+        let mut cmd = git_in(&storage, dir.path());
+        cmd.args(args);
+        cmd
+    };
+    let set = |entry: &str, password: &str| {
+        page_in_store()
+            .arg("edit")
+            .arg(entry)
+            .write_stdin(password.to_string())
+            .assert()
+            .success();
+    };
+    let show = |entry: &str| {
+        page_in_store()
+            .arg("show")
+            .arg("--on-screen")
+            .arg(entry)
+            .assert()
+            .success()
+            .get_output()
+            .stdout
+            .clone()
+    };
+
+    std::fs::create_dir_all(&storage).unwrap();
+    git(&["init", "-q", "-b", "main"]).assert().success();
+    git(&["config", "user.name", "page"]).assert().success();
+    git(&["config", "user.email", "page@example.com"])
+        .assert()
+        .success();
+    page_in_store().arg("init").assert().success();
+    for entry in ["a", "b"] {
+        page_in_store()
+            .arg("new")
+            .arg(entry)
+            .write_stdin(entry.to_string())
+            .assert()
+            .success();
+    }
+    page_in_store()
+        .arg("git-merge-driver")
+        .arg("--install")
+        .assert()
+        .success();
+    assert_eq!(
+        std::fs::read_to_string(storage.join(".gitattributes")).unwrap(),
+        "entries.toml.age merge=page\n"
+    );
+    git(&["add", "-A"]).assert().success();
+    git(&["commit", "-qm", "init"]).assert().success();
+
+    git(&["checkout", "-qb", "other"]).assert().success();
+    set("a", "a-other");
+    git(&["commit", "-qam", "other"]).assert().success();
+    git(&["checkout", "-q", "main"]).assert().success();
+    set("b", "b-main");
+    git(&["commit", "-qam", "main"]).assert().success();
+
+    git(&["merge", "--no-edit", "other"])
+        .assert()
+        .success()
+        .stderr(predicate::str::contains(
+            "Updated 'a'\nMerged 1 change(s), resolved 0 conflict(s)",
+        ));
+    assert_eq!(show("a"), b"a-other\n");
+    assert_eq!(show("b"), b"b-main\n");
+
+    // conflicting changes are left to `page merge`
+    git(&["checkout", "-qb", "conflict"]).assert().success();
+    set("a", "a-conflict");
+    git(&["commit", "-qam", "conflict"]).assert().success();
+    git(&["checkout", "-q", "main"]).assert().success();
+    set("a", "a-main");
+    git(&["commit", "-qam", "main"]).assert().success();
+    git(&["merge", "--no-edit", "conflict"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "Error: conflicting changes to 'a', merge the other revision with `page merge`",
+        ));
+    assert_eq!(show("a"), b"a-main\n");
+}
+
+#[test]
+fn git_diff_textconv() {
+    let dir = tempdir();
+    let storage = dir.path().join("store");
+    let page_in_store = || page_unlocked_in(&storage, dir.path());
+    let git = |args: &[&str]| {
+        let mut cmd = git_in(&storage, dir.path());
+        cmd.args(args);
+        cmd
+    };
+
+    std::fs::create_dir_all(&storage).unwrap();
+    git(&["init", "-q", "-b", "main"]).assert().success();
+    git(&["config", "user.name", "page"]).assert().success();
+    git(&["config", "user.email", "page@example.com"])
+        .assert()
+        .success();
+    page_in_store().arg("init").assert().success();
+    page_in_store()
+        .arg("new")
+        .arg("github")
+        .arg("--username")
+        .arg("me")
+        .arg("--field")
+        .arg("token=hunter2")
+        .write_stdin("secret-password")
+        .assert()
+        .success();
+    page_in_store()
+        .arg("new")
+        .arg("bank")
+        .write_stdin("other-password")
+        .assert()
+        .success();
+
+    let listing = page_in_store()
+        .arg("git-diff-textconv")
+        .arg(storage.join("entries.toml.age"))
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let listing = String::from_utf8(listing).unwrap();
+    let pattern = "^\\[bank\\]\npassword = [0-9a-f]{12}\n\n\\[github\\]\nusername = \"me\"\npassword = [0-9a-f]{12}\nfield \"token\" = [0-9a-f]{12}\n$";
+    assert!(
+        predicate::str::is_match(pattern).unwrap().eval(&listing),
+        "{}",
+        listing
+    );
+
+    page_in_store()
+        .arg("git-diff-textconv")
+        .arg("--install")
+        .assert()
+        .success();
+    git(&["add", "-A"]).assert().success();
+    git(&["commit", "-qm", "init"]).assert().success();
+
+    page_in_store()
+        .arg("edit")
+        .arg("github")
+        .arg("--username")
+        .arg("you")
+        .arg("--no-prompt")
+        .assert()
+        .success();
+    git(&["diff"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(
+            "-username = \"me\"\n+username = \"you\"\n",
+        ))
+        .stdout(predicate::str::contains("secret-password").not())
+        .stdout(predicate::str::contains("hunter2").not());
+    // git keeps the listing of the committed revision instead of decrypting it again
+    git(&["rev-parse", "--verify", "-q", "refs/notes/textconv/page"])
+        .assert()
+        .success();
+}
+
+#[test]
+fn git_diff_textconv_fingerprints() {
+    let dir = tempdir();
+    let page_in_store = |store: &str| page_unlocked_in(&dir.path().join(store), dir.path());
+    let listing = |store: &str| {
+        let output = page_in_store(store)
+            .arg("git-diff-textconv")
+            .arg(dir.path().join(store).join("entries.toml.age"))
+            .assert()
+            .success()
+            .get_output()
+            .stdout
+            .clone();
+        String::from_utf8(output).unwrap()
+    };
+
+    page_in_store("plain").arg("init").assert().success();
+    page_in_store("with-key")
+        .arg("init")
+        .arg("--key-file")
+        .arg(dir.path().join("key.txt"))
+        .assert()
+        .success();
+    for store in ["plain", "with-key"] {
+        page_in_store(store)
+            .arg("new")
+            .arg("entry")
+            .write_stdin("password")
+            .assert()
+            .success();
+    }
+
+    // the same for a value whenever it is listed, but not without the key file
+    let plain = listing("plain");
+    assert!(plain.starts_with("[entry]\npassword = "), "{}", plain);
+    assert_eq!(plain, listing("plain"));
+    assert_ne!(plain, listing("with-key"));
+}
+
+#[test]
+fn git_sync() {
+    let dir = tempdir();
+    let remote = dir.path().join("remote.git");
+    std::fs::write(
+        dir.path().join(".gitconfig"),
+        "[init]\n\tdefaultBranch = main\n",
+    )
+    .unwrap();
+    let page_on = |device: &str| {
+        let mut cmd = page_unlocked_in(&dir.path().join(device), dir.path());
+        isolate_git(&mut cmd, dir.path());
+        cmd
+    };
+    let git = |args: &[&str]| {
+        let mut cmd = git_in(dir.path(), dir.path());
+        cmd.args(args);
+        cmd
+    };
+    let new = |device: &str, entry: &str| {
+        page_on(device)
+            .arg("new")
+            .arg(entry)
+            .write_stdin(entry.to_string())
+            .assert()
+            .success();
+    };
+
+    git(&["init", "-q", "--bare", "remote.git"])
+        .assert()
+        .success();
+    page_on("laptop").arg("init").assert().success();
+    new("laptop", "github");
+    page_on("laptop")
+        .arg("git")
+        .arg("sync")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("is not a git repository"));
+    page_on("laptop")
+        .arg("git")
+        .arg("init")
+        .arg("--remote")
+        .arg(&remote)
+        .assert()
+        .success();
+    page_on("laptop")
+        .arg("git")
+        .arg("sync")
+        .assert()
+        .success()
+        .stdout("Synced with origin/main\n");
+
+    git(&["clone", "-q", "remote.git", "desktop"])
+        .assert()
+        .success();
+    page_on("desktop").arg("git").arg("init").assert().success();
+    for setting in ["git_auto_commit", "git_auto_pull"] {
+        page_on("laptop")
+            .arg("config")
+            .arg("set")
+            .arg("--user")
+            .arg(setting)
+            .arg("true")
+            .assert()
+            .success();
+    }
+
+    // changes are committed and pushed on save, and pulled before loading
+    new("desktop", "bank");
+    page_on("laptop")
+        .arg("list")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("bank"));
+    page_on("laptop")
+        .arg("git")
+        .arg("log")
+        .arg("--format=%s")
+        .assert()
+        .success()
+        .stdout("new_entry: bank\nAdd page store\n");
+
+    // concurrent changes to different entries are merged
+    page_on("laptop")
+        .arg("config")
+        .arg("set")
+        .arg("--user")
+        .arg("git_auto_pull")
+        .arg("false")
+        .assert()
+        .success();
+    new("desktop", "mail");
+    new("laptop", "wifi");
+    page_on("laptop")
+        .arg("git")
+        .arg("status")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("ahead 1, behind 1"));
+    // the merge driver asks for the passphrase without a passphrase file
+    page_on("laptop")
+        .arg("config")
+        .arg("set")
+        .arg("--user")
+        .arg("keyring")
+        .arg("false")
+        .assert()
+        .success();
+    page_on("laptop")
+        .env_remove("PAGE_PASSPHRASE_FILE")
+        .arg("git")
+        .arg("sync")
+        .write_stdin("master\n")
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("Enter passphrase: "))
+        .stderr(predicate::str::contains("Added 'mail'"));
+    let list = page_on("laptop").arg("list").assert().success();
+    let list = String::from_utf8(list.get_output().stdout.clone()).unwrap();
+    for entry in ["bank", "github", "mail", "wifi"] {
+        assert!(list.contains(entry), "{}", list);
+    }
+
+    // without the remote, changes are still saved and committed
+    std::fs::rename(&remote, dir.path().join("offline.git")).unwrap();
+    page_on("laptop")
+        .arg("new")
+        .arg("vpn")
+        .write_stdin("vpn")
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("run `page git sync` later"));
+    page_on("laptop").arg("git").arg("sync").assert().failure();
+    std::fs::rename(dir.path().join("offline.git"), &remote).unwrap();
+    page_on("laptop").arg("git").arg("sync").assert().success();
+    page_on("desktop").arg("git").arg("sync").assert().success();
+    page_on("desktop")
+        .arg("show")
+        .arg("--on-screen")
+        .arg("vpn")
+        .assert()
+        .success()
+        .stdout("vpn\n");
+}
+
+#[test]
+fn git_sync_without_upstream() {
+    let dir = tempdir();
+    let remote = dir.path().join("remote.git");
+    std::fs::write(
+        dir.path().join(".gitconfig"),
+        "[init]\n\tdefaultBranch = main\n",
+    )
+    .unwrap();
+    let page_on = |device: &str| {
+        let mut cmd = page_unlocked_in(&dir.path().join(device), dir.path());
+        isolate_git(&mut cmd, dir.path());
+        cmd
+    };
+    let git = |args: &[&str]| {
+        let mut cmd = git_in(dir.path(), dir.path());
+        cmd.args(args);
+        cmd
+    };
+    let new = |device: &str, entry: &str| {
+        page_on(device)
+            .arg("new")
+            .arg(entry)
+            .write_stdin(entry.to_string())
+            .assert()
+            .success();
+    };
+    let git_init = |remote: &Path| {
+        let mut cmd = page_on("laptop");
+        cmd.arg("git").arg("init").arg("--remote").arg(remote);
+        cmd
+    };
+
+    git(&["init", "-q", "--bare", "remote.git"])
+        .assert()
+        .success();
+    page_on("laptop").arg("init").assert().success();
+    new("laptop", "github");
+    git_init(&remote).assert().success();
+    // origin is kept if it is the same remote
+    git_init(&remote).assert().success();
+    git_init(&dir.path().join("other.git"))
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "origin is already set to another remote",
+        ));
+    page_on("laptop").arg("git").arg("sync").assert().success();
+
+    git(&["clone", "-q", "remote.git", "desktop"])
+        .assert()
+        .success();
+    new("desktop", "bank");
+    page_on("desktop").arg("git").arg("sync").assert().success();
+
+    // e.g. origin added by hand, the branch of origin is pulled before pushing
+    git(&["-C", "laptop", "branch", "--unset-upstream"])
+        .assert()
+        .success();
+    new("laptop", "wifi");
+    page_on("laptop")
+        .arg("git")
+        .arg("sync")
+        .assert()
+        .success()
+        .stdout("Synced with origin/main\n");
+    let list = page_on("laptop").arg("list").assert().success();
+    let list = String::from_utf8(list.get_output().stdout.clone()).unwrap();
+    for entry in ["bank", "github", "wifi"] {
+        assert!(list.contains(entry), "{}", list);
+    }
+}
+
+/// Writes an entries file holding the given document, as another version of
+/// `page` might have
+fn write_entries_document(storage: &Path, document: &str, passphrase: &str) {
+    use std::io::Write;
+
+    let encryptor = age::Encryptor::with_user_passphrase(age::secrecy::SecretString::from(
+        passphrase.to_string(),
+    ));
+    let mut encrypted = vec![];
+    let mut writer = encryptor.wrap_output(&mut encrypted).unwrap();
+    writer.write_all(document.as_bytes()).unwrap();
+    writer.finish().unwrap();
+    std::fs::create_dir_all(storage).unwrap();
+    std::fs::write(storage.join("entries.toml.age"), encrypted).unwrap();
+}
+
+#[test]
+fn fmt() {
+    let dir = tempdir();
+    let page_in_store = || page_in(dir.path(), dir.path());
+    let fmt = |check: bool| {
+        let mut cmd = page_in_store();
+        cmd.arg("fmt");
+        if check {
+            cmd.arg("--check");
+        }
+        cmd.write_stdin("master");
+        cmd
+    };
+
+    write_entries_document(
+        dir.path(),
+        "[github]\nusername = \"me\"\npassword = \"1\"\n\n[bank]\npassword = \"2\"\n\n[bank.fields]\npin = \"0000\"\naccount = \"42\"\n",
+        "master",
+    );
+    page_in_store()
+        .arg("list")
+        .write_stdin("master")
+        .assert()
+        .success()
+        .stdout(predicate::str::ends_with("bank\ngithub\n"));
+    fmt(true)
+        .assert()
+        .failure()
+        .stderr("Error: the entries file is not canonical, run `page fmt`\n");
+    fmt(false)
+        .assert()
+        .success()
+        .stdout(predicate::str::ends_with("Formatted the entries file\n"));
+    fmt(true)
+        .assert()
+        .success()
+        .stdout(predicate::str::ends_with("The entries file is canonical\n"));
+    page_in_store()
+        .arg("show")
+        .arg("--on-screen")
+        .arg("--field")
+        .arg("pin")
+        .arg("bank")
+        .write_stdin("master")
+        .assert()
+        .success()
+        .stdout(predicate::str::ends_with("0000\n"));
+
+    // saving would drop what page doesn't know, so it isn't formatted
+    write_entries_document(
+        dir.path(),
+        "[github]\npassword = \"1\"\ncolor = \"red\"\n",
+        "master",
+    );
+    fmt(false)
+        .assert()
+        .failure()
+        .stderr("Error: the entries file contains unknown keys, which saving it would drop\n");
+}
+
+#[test]
+fn file_per_entry_layout() {
+    let dir = tempdir();
+    let storage = dir.path().join("store");
+    let entries = storage.join("entries");
+    let page_in_store = || page_in(&storage, dir.path());
+    let list = || {
+        page_in_store()
+            .arg("list")
+            .write_stdin("master")
+            .assert()
+            .success()
+    };
+
+    page_in_store()
+        .arg("init")
+        .arg("--layout")
+        .arg("file-per-entry")
+        .write_stdin("master")
+        .assert()
+        .success()
+        .stdout(predicate::str::starts_with(format!(
+            "Created entries folder {}",
+            entries.display()
+        )));
+    assert_eq!(
+        std::fs::read_to_string(storage.join("store.toml")).unwrap(),
+        "layout = \"file-per-entry\"\n"
+    );
+    assert!(storage.join("identity.age").exists());
+    assert!(!storage.join("entries.toml.age").exists());
+    page_in_store()
+        .arg("init")
+        .arg("--layout")
+        .arg("single-file")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "the layout can only be chosen for a new store",
+        ));
+
+    for (entry, password) in [("github", "1"), ("work/vpn", "2")] {
+        page_in_store()
+            .arg("new")
+            .arg(entry)
+            .write_stdin(format!("master\n{}", password))
+            .assert()
+            .success();
+    }
+    page_in_store()
+        .arg("new")
+        .arg("../outside")
+        .write_stdin("master\n3")
+        .assert()
+        .failure()
+        .stderr("Error: '../outside' can't be used as the file name of an entry\n");
+    page_in_store()
+        .arg("new")
+        .arg("work:vpn")
+        .write_stdin("master\n3")
+        .assert()
+        .failure()
+        .stderr("Error: 'work:vpn' can't be used as the file name of an entry\n");
+    // saving either would overwrite the other on a case-insensitive file system
+    for entry in ["GitHub", "Work/mail"] {
+        page_in_store()
+            .arg("new")
+            .arg(entry)
+            .write_stdin("master\n3")
+            .assert()
+            .failure()
+            .stderr(predicate::str::contains(
+                "a case-insensitive file system doesn't tell their files apart",
+            ));
+    }
+    assert!(!storage.join("entries/GitHub.age").exists());
+    list().stdout(predicate::str::ends_with("github\nwork/vpn\n"));
+    page_in_store()
+        .arg("info")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(format!(
+            "Storage folder: {}\n",
+            entries.display()
+        )));
+
+    // saving only writes the entries which changed
+    let github = std::fs::read(entries.join("github.age")).unwrap();
+    page_in_store()
+        .arg("edit")
+        .arg("work/vpn")
+        .write_stdin("master\n4")
+        .assert()
+        .success();
+    assert_eq!(std::fs::read(entries.join("github.age")).unwrap(), github);
+    page_in_store()
+        .arg("fmt")
+        .arg("--check")
+        .write_stdin("master")
+        .assert()
+        .success()
+        .stdout(predicate::str::ends_with(
+            "The entries folder is canonical\n",
+        ));
+
+    // a damaged entry file doesn't keep the other entries from being used,
+    // and saving leaves it alone
+    std::fs::write(entries.join("github.age"), "damaged").unwrap();
+    list()
+        .stdout(predicate::str::ends_with("work/vpn\n"))
+        .stderr(predicate::str::contains("Warning: skipping entry 'github'"));
+    page_in_store()
+        .arg("edit")
+        .arg("work/vpn")
+        .write_stdin("master\n5")
+        .assert()
+        .success();
+    assert_eq!(
+        std::fs::read_to_string(entries.join("github.age")).unwrap(),
+        "damaged"
+    );
+
+    page_in_store()
+        .arg("remove")
+        .arg("work/vpn")
+        .write_stdin("master")
+        .assert()
+        .success();
+    assert!(!entries.join("work").exists());
+
+    page_in_store()
+        .arg("list")
+        .write_stdin("wrong")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("Failed to decrypt identity file"));
+}
+
+#[test]
+fn file_per_entry_passwd() {
+    let dir = tempdir();
+    let storage = dir.path().join("store");
+    let page_in_store = || page_in(&storage, dir.path());
+    let show = |passphrase: &str| {
+        page_in_store()
+            .arg("show")
+            .arg("--on-screen")
+            .arg("entry")
+            .write_stdin(passphrase.to_string())
+            .assert()
+    };
+
+    page_in_store()
+        .arg("init")
+        .arg("--layout")
+        .arg("file-per-entry")
+        .write_stdin("master")
+        .assert()
+        .success();
+    page_in_store()
+        .arg("new")
+        .arg("entry")
+        .write_stdin("master\npassword")
+        .assert()
+        .success();
+
+    // the identity file is kept if the new one can't be written
+    let new_identity = storage.join("identity.age.new");
+    std::fs::create_dir(&new_identity).unwrap();
+    page_in_store()
+        .arg("passwd")
+        .write_stdin("master\nnew\nnew")
+        .assert()
+        .failure();
+    show("master").success();
+    assert!(!storage.join("entries/entry.age.new").exists());
+    std::fs::remove_dir(&new_identity).unwrap();
+
+    let old_identity = std::fs::read(storage.join("identity.age")).unwrap();
+    page_in_store()
+        .arg("passwd")
+        .write_stdin("master\nnew\nnew")
+        .assert()
+        .success();
+    assert!(!new_identity.exists());
+    show("new")
+        .success()
+        .stdout(enter_passphrase_show("password"));
+    show("master").failure();
+
+    // the identity file in the git history doesn't decrypt the entries anymore
+    std::fs::write(storage.join("identity.age"), old_identity).unwrap();
+    show("master")
+        .failure()
+        .stderr(predicate::str::contains("skipping entry 'entry'"));
+}
+
+#[test]
+fn file_per_entry_git() {
+    let dir = tempdir();
+    let storage = dir.path().join("store");
+    let page_in_store = || page_unlocked_in(&storage, dir.path());
+    let git = |args: &[&str]| {
+        let mut cmd = git_in(&storage, dir.path());
+        cmd.args(args);
+        cmd
+    };
+    let set = |entry: &str, username: &str| {
+        page_in_store()
+            .arg("edit")
+            .arg(entry)
+            .arg("--username")
+            .arg(username)
+            .arg("--no-prompt")
+            .assert()
+            .success();
+    };
+
+    std::fs::create_dir_all(&storage).unwrap();
+    git(&["init", "-q", "-b", "main"]).assert().success();
+    git(&["config", "user.name", "page"]).assert().success();
+    git(&["config", "user.email", "page@example.com"])
+        .assert()
+        .success();
+    page_in_store()
+        .arg("init")
+        .arg("--layout")
+        .arg("file-per-entry")
+        .assert()
+        .success();
+    for entry in ["a", "b"] {
+        page_in_store()
+            .arg("new")
+            .arg(entry)
+            .write_stdin(entry.to_string())
+            .assert()
+            .success();
+    }
+    page_in_store()
+        .arg("git-merge-driver")
+        .arg("--install")
+        .arg("--prefer")
+        .arg("theirs")
+        .assert()
+        .success();
+    page_in_store()
+        .arg("git-diff-textconv")
+        .arg("--install")
+        .assert()
+        .success();
+    assert_eq!(
+        std::fs::read_to_string(storage.join(".gitattributes")).unwrap(),
+        "entries/**/*.age merge=page\nentries/**/*.age diff=page\n"
+    );
+    git(&["add", "-A"]).assert().success();
+    git(&["commit", "-qm", "init"]).assert().success();
+
+    // only the changed entry is in the diff
+    set("a", "me");
+    git(&["diff"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("entries/a.age"))
+        .stdout(predicate::str::contains("+username = \"me\"\n"))
+        .stdout(predicate::str::contains("entries/b.age").not());
+    git(&["commit", "-qam", "a"]).assert().success();
+
+    // changes to the same entry on both branches go through the driver
+    git(&["checkout", "-qb", "other"]).assert().success();
+    set("a", "other");
+    set("b", "other");
+    git(&["commit", "-qam", "other"]).assert().success();
+    git(&["checkout", "-q", "main"]).assert().success();
+    set("a", "main");
+    git(&["commit", "-qam", "main"]).assert().success();
+    git(&["merge", "--no-edit", "other"])
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("Conflict on 'a', kept theirs"));
+    for entry in ["a", "b"] {
+        page_in_store()
+            .arg("show")
+            .arg("--on-screen")
+            .arg("--attribute")
+            .arg("username")
+            .arg(entry)
+            .assert()
+            .success()
+            .stdout("other\n");
+    }
+}
+
+#[test]
+fn backups_and_conflicts() {
+    use std::io::{Read, Write};
+    use std::process::Stdio;
+
+    let dir = tempdir();
+    let storage = dir.path().join("store");
+    let passphrase_file = passphrase_file(dir.path());
+    let page_in_store = || page_unlocked_in(&storage, dir.path());
+    let backups = || {
+        let output = page_in_store()
+            .arg("backups")
+            .assert()
+            .success()
+            .get_output()
+            .stdout
+            .clone();
+        String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(String::from)
+            .collect::<Vec<_>>()
+    };
+
+    page_in_store()
+        .arg("init")
+        .arg("--work-factor")
+        .arg("10")
+        .assert()
+        .success();
+    assert!(backups().is_empty());
+    for (command, password) in [("new", "1"), ("edit", "2")] {
+        page_in_store()
+            .arg(command)
+            .arg("github")
+            .write_stdin(password)
+            .assert()
+            .success();
+    }
+    let listed = backups();
+    assert_eq!(listed.len(), 2);
+    assert!(listed[0].starts_with(&storage.join("backups").display().to_string()));
+    assert!(listed[0] > listed[1], "{:?}", listed);
+
+    // a backup is an entries file, which restores the entry as it was
+    page_in_store()
+        .arg("merge")
+        .arg("--base")
+        .arg(storage.join("entries.toml.age"))
+        .arg(&listed[0])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Updated 'github'"));
+    page_in_store()
+        .arg("show")
+        .arg("--on-screen")
+        .arg("github")
+        .assert()
+        .success()
+        .stdout("1\n");
+
+    for _ in 0..10 {
+        page_in_store()
+            .arg("edit")
+            .arg("github")
+            .arg("--username")
+            .arg("me")
+            .arg("--no-prompt")
+            .assert()
+            .success();
+    }
+    assert_eq!(backups().len(), 10);
+
+    // an edit doesn't overwrite a change saved while it waited for the password
+    let mut edit = page_process_in(&storage, dir.path())
+        .env("PAGE_PASSPHRASE_FILE", &passphrase_file)
+        .args(["edit", "github"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut prompt = vec![];
+    let mut stdout = edit.stdout.take().unwrap();
+    while !String::from_utf8_lossy(&prompt).contains("Password for 'github'") {
+        let mut byte = [0];
+        assert_eq!(stdout.read(&mut byte).unwrap(), 1);
+        prompt.push(byte[0]);
+    }
+    page_in_store()
+        .arg("edit")
+        .arg("github")
+        .arg("--username")
+        .arg("other")
+        .arg("--no-prompt")
+        .assert()
+        .success();
+    edit.stdin.take().unwrap().write_all(b"3\n").unwrap();
+    let output = edit.wait_with_output().unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr)
+        .contains("was changed since the store was loaded, run the command again"));
+    page_in_store()
+        .arg("show")
+        .arg("--on-screen")
+        .arg("--attribute")
+        .arg("username")
+        .arg("github")
+        .assert()
+        .success()
+        .stdout("other\n");
+}
+
+#[test]
+fn backups_passwd_and_git() {
+    let dir = tempdir();
+    let storage = dir.path().join("store");
+    let page_in_store = || {
+        let mut cmd = page_in(&storage, dir.path());
+        isolate_git(&mut cmd, dir.path());
+        cmd
+    };
+    let edit = |passphrase: &str| {
+        page_in_store()
+            .arg("edit")
+            .arg("github")
+            .arg("--username")
+            .arg(passphrase)
+            .arg("--no-prompt")
+            .write_stdin(passphrase.to_string())
+            .assert()
+            .success();
+    };
+    let backups = || std::fs::read_dir(storage.join("backups")).unwrap().count();
+
+    page_in_store()
+        .arg("init")
+        .arg("--work-factor")
+        .arg("10")
+        .write_stdin("master")
+        .assert()
+        .success();
+    page_in_store()
+        .arg("new")
+        .arg("github")
+        .write_stdin("master\npassword")
+        .assert()
+        .success();
+    edit("master");
+    assert_eq!(backups(), 2);
+
+    // the backups are encrypted with the old passphrase
+    page_in_store()
+        .arg("passwd")
+        .write_stdin("master\nnew\nnew")
+        .assert()
+        .success();
+    assert_eq!(backups(), 0);
+    edit("new");
+    assert_eq!(backups(), 1);
+
+    page_in_store().arg("git").arg("init").assert().success();
+    assert_eq!(
+        std::fs::read_to_string(storage.join(".gitignore")).unwrap(),
+        "backups/\n"
+    );
+    page_in_store()
+        .arg("git")
+        .arg("status")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("backups").not());
+}
+
+/// The objects by path, with their ETag
+type Objects =
+    std::sync::Arc<std::sync::Mutex<std::collections::HashMap<String, (Vec<u8>, String)>>>;
+
+/// A stand-in for an S3-compatible server like MinIO, keeping objects in
+/// memory in the bucket `passwords`. Conditional writes are handled like S3
+/// does, ETags are a counter.
+struct S3StandIn {
+    endpoint: String,
+    objects: Objects,
+    /// The access key id of each request
+    access_keys: std::sync::Arc<std::sync::Mutex<Vec<String>>>,
+}
+
+fn s3_stand_in() -> S3StandIn {
+    use std::io::{BufRead, BufReader, Read, Write};
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let stand_in = S3StandIn {
+        endpoint: format!("http://{}", listener.local_addr().unwrap()),
+        objects: Default::default(),
+        access_keys: Default::default(),
+    };
+    let (objects, access_keys) = (stand_in.objects.clone(), stand_in.access_keys.clone());
+    std::thread::spawn(move || {
+        let mut writes = 0;
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let request: Vec<String> = line.split_whitespace().map(String::from).collect();
+            let (method, path) = (request[0].as_str(), request[1].clone());
+            let mut headers = std::collections::HashMap::new();
+            loop {
+                line.clear();
+                reader.read_line(&mut line).unwrap();
+                match line.trim_end().split_once(": ") {
+                    Some((name, value)) => {
+                        headers.insert(name.to_lowercase(), value.to_string());
+                    }
+                    None => break,
+                }
+            }
+            let mut body = vec![
+                0;
+                headers
+                    .get("content-length")
+                    .map_or(0, |n| n.parse().unwrap())
+            ];
+            reader.read_exact(&mut body).unwrap();
+
+            let access_key = headers
+                .get("authorization")
+                .and_then(|auth| auth.strip_prefix("AWS4-HMAC-SHA256 Credential="))
+                .and_then(|credential| credential.split('/').next());
+            let mut objects = objects.lock().unwrap();
+            let not_found = |code: &str, message: &str| {
+                let error = format!(
+                    "<Error><Code>{}</Code><Message>{}</Message></Error>",
+                    code, message
+                );
+                ("404 Not Found", None, error.into_bytes())
+            };
+            let (status, etag, content) = match (access_key, method) {
+                (None, _) => ("403 Forbidden", None, vec![]),
+                (Some(_), _) if !path.starts_with("/passwords/") => {
+                    not_found("NoSuchBucket", "The specified bucket does not exist")
+                }
+                (Some(_), "GET" | "HEAD") => match objects.get(&path) {
+                    Some((bytes, etag)) => ("200 OK", Some(etag.clone()), bytes.clone()),
+                    None => not_found("NoSuchKey", "The specified key does not exist."),
+                },
+                (Some(_), "PUT") => {
+                    let current = objects.get(&path).map(|(_, etag)| etag.as_str());
+                    let allowed = match (headers.get("if-match"), headers.get("if-none-match")) {
+                        (Some(expected), _) => current == Some(expected.as_str()),
+                        (None, Some(any)) if any == "*" => current.is_none(),
+                        _ => true,
+                    };
+                    if allowed {
+                        writes += 1;
+                        let etag = format!("\"{}\"", writes);
+                        objects.insert(path, (body, etag.clone()));
+                        ("200 OK", Some(etag), vec![])
+                    } else {
+                        ("412 Precondition Failed", None, vec![])
+                    }
+                }
+                _ => ("405 Method Not Allowed", None, vec![]),
+            };
+            if let Some(access_key) = access_key {
+                access_keys.lock().unwrap().push(access_key.to_string());
+            }
+            let mut response = format!(
+                "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+                status,
+                content.len()
+            );
+            if let Some(etag) = etag {
+                response.push_str(&format!("ETag: {}\r\n", etag));
+            }
+            response.push_str("\r\n");
+            stream.write_all(response.as_bytes()).unwrap();
+            if method != "HEAD" {
+                stream.write_all(&content).unwrap();
+            }
+        }
+    });
+    stand_in
+}
+
+#[test]
+fn s3_backend() {
+    use std::io::{Read, Write};
+    use std::process::Stdio;
+
+    let s3 = s3_stand_in();
+    let dir = tempdir();
+    let storage = dir.path().join("store");
+    let passphrase_file = passphrase_file(dir.path());
+    std::fs::create_dir_all(&storage).unwrap();
+    std::fs::write(
+        storage.join("store.toml"),
+        format!(
+            "[s3]\nendpoint = \"{}\"\nbucket = \"passwords\"\nkey = \"team/entries.toml.age\"\n",
+            s3.endpoint
+        ),
+    )
+    .unwrap();
+    let page_process = || {
+        let mut cmd = page_process_in(&storage, dir.path());
+        cmd.env("PAGE_PASSPHRASE_FILE", &passphrase_file)
+            .env("AWS_ACCESS_KEY_ID", "AKIDENV")
+            .env("AWS_SECRET_ACCESS_KEY", "secret");
+        cmd
+    };
+    let page_in_store = || Command::from_std(page_process());
+    let object = || {
+        s3.objects
+            .lock()
+            .unwrap()
+            .get("/passwords/team/entries.toml.age")
+            .cloned()
+    };
+
+    page_in_store()
+        .env_remove("AWS_ACCESS_KEY_ID")
+        .arg("init")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("no S3 credentials"));
+    page_in_store()
+        .arg("init")
+        .arg("--work-factor")
+        .arg("10")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(format!(
+            "Created entries file {}/passwords/team/entries.toml.age",
+            s3.endpoint
+        )));
+    assert!(std::fs::read_to_string(storage.join("store.toml"))
+        .unwrap()
+        .contains("work_factor = 10\n\n[s3]\n"));
+    page_in_store()
+        .arg("new")
+        .arg("github")
+        .write_stdin("1")
+        .assert()
+        .success();
+
+    // only the age ciphertext is stored, nothing is kept locally
+    let (bytes, _) = object().unwrap();
+    assert!(bytes.starts_with(b"age-encryption.org/v1"));
+    assert!(!storage.join("entries.toml.age").exists());
+    page_in_store()
+        .arg("show")
+        .arg("--on-screen")
+        .arg("github")
+        .assert()
+        .success()
+        .stdout("1\n");
+    assert!(s3
+        .access_keys
+        .lock()
+        .unwrap()
+        .iter()
+        .all(|key| key == "AKIDENV"));
+
+    // an edit doesn't overwrite a change saved while it waited for the password
+    let mut edit = page_process()
+        .args(["edit", "github"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut prompt = vec![];
+    let mut stdout = edit.stdout.take().unwrap();
+    while !String::from_utf8_lossy(&prompt).contains("Password for 'github'") {
+        let mut byte = [0];
+        assert_eq!(stdout.read(&mut byte).unwrap(), 1);
+        prompt.push(byte[0]);
+    }
+    page_in_store()
+        .arg("edit")
+        .arg("github")
+        .arg("--username")
+        .arg("other")
+        .arg("--no-prompt")
+        .assert()
+        .success();
+    let saved = object().unwrap();
+    edit.stdin.take().unwrap().write_all(b"3\n").unwrap();
+    let output = edit.wait_with_output().unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr)
+        .contains("was changed since the store was loaded, run the command again"));
+    assert_eq!(object(), Some(saved));
+
+    // the credentials can be kept in an entry of another store
+    let keys = dir.path().join("keys");
+    page_in_store()
+        .arg("store")
+        .arg("add")
+        .arg("keys")
+        .arg(&keys)
+        .assert()
+        .success();
+    page_in_store()
+        .arg("--store")
+        .arg("keys")
+        .arg("init")
+        .arg("--work-factor")
+        .arg("10")
+        .assert()
+        .success();
+    page_in_store()
+        .arg("--store")
+        .arg("keys")
+        .arg("new")
+        .arg("s3")
+        .arg("--field")
+        .arg("aws_access_key_id=AKIDENTRY")
+        .arg("--field")
+        .arg("aws_secret_access_key=secret")
+        .write_stdin("unused")
+        .assert()
+        .success();
+    let settings = std::fs::read_to_string(storage.join("store.toml")).unwrap();
+    std::fs::write(
+        storage.join("store.toml"),
+        format!("{}credentials = \"keys:s3\"\n", settings),
+    )
+    .unwrap();
+    s3.access_keys.lock().unwrap().clear();
+    page_in_store()
+        .env_remove("AWS_ACCESS_KEY_ID")
+        .env_remove("AWS_SECRET_ACCESS_KEY")
+        .arg("show")
+        .arg("--on-screen")
+        .arg("--attribute")
+        .arg("username")
+        .arg("github")
+        .assert()
+        .success()
+        .stdout("other\n");
+    let access_keys = s3.access_keys.lock().unwrap().clone();
+    assert!(!access_keys.is_empty());
+    assert!(access_keys.iter().all(|key| key == "AKIDENTRY"));
+
+    // a missing bucket isn't taken for a store which wasn't created yet
+    let elsewhere = dir.path().join("elsewhere");
+    std::fs::create_dir_all(&elsewhere).unwrap();
+    std::fs::write(
+        elsewhere.join("store.toml"),
+        format!(
+            "[s3]\nendpoint = \"{}\"\nbucket = \"missing\"\nkey = \"entries.toml.age\"\n",
+            s3.endpoint
+        ),
+    )
+    .unwrap();
+    page_in(&elsewhere, dir.path())
+        .env("PAGE_PASSPHRASE_FILE", &passphrase_file)
+        .env("AWS_ACCESS_KEY_ID", "AKIDENV")
+        .env("AWS_SECRET_ACCESS_KEY", "secret")
+        .arg("list")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "failed with 404 The specified bucket does not exist",
+        ));
+}